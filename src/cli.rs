@@ -0,0 +1,401 @@
+use crate::document::ParseError;
+use crate::fetch::RequestOptions;
+use crate::fetch::charset;
+use crate::fetch::cache::{CacheOptions, DEFAULT_CACHE_TTL};
+use crate::fetch::limits::FetchLimits;
+use crate::fetch::politeness::PolitenessOptions;
+use crate::template_parser::OnError;
+use crate::template_parser::sandbox::SandboxPolicy;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const USAGE: &str = "Usage: dessert3 [OPTIONS] <TEMPLATE>
+       dessert3 [OPTIONS] check <FILE>
+       dessert3 [OPTIONS] ast <FILE>
+       dessert3 [OPTIONS] repl <URL-OR-FILE>
+       dessert3 [OPTIONS] suggest <URL-OR-FILE> --example <TEXT>...
+
+Commands:
+    check <FILE>              Checks the template in the file without fetching any document
+    ast <FILE>                Prints the tree of the nodes of the template in the file
+    repl <URL-OR-FILE>        Fetches the page once, then shows the matches of the typed CSS selectors or
+                              the output of the typed template snippets
+    suggest <URL-OR-FILE>     Suggests the selector of the nodes like the examples in the page, and a loop over them
+
+Options:
+    --output <FILE>           Writes the output into the file instead of the stdout
+    --provenance <FILE>       Writes where each value of the output came from into the file, as JSON lines
+    --on-error <MODE>         What to do with a failing token or loop iteration: abort, skip or placeholder, abort by default
+    --json                    Prints the tree of `ast` as JSON
+    --example <TEXT>          A text of the page that `suggest` looks for, can be repeated
+    --trace                   Logs each fetch, selector and emitted value to the stderr
+    --plan                    Prints the documents that the template requests, without rendering it
+    --header <NAME: VALUE>    Adds the header to all the requests, can be repeated
+    --user-agent <AGENT>      Sets the User-Agent of all the requests
+    --cookie-jar <FILE>       Sends the cookies of the Netscape cookie file, and saves the received cookies back to it
+    --timeout <SECONDS>       The timeout of each request, 30 by default
+    --retries <N>             How many times to retry a request failed by a connection error, a timeout, 429 or 5xx, 2 by default
+    --retry-delay <SECONDS>   The delay before the first retry, doubled for each of the next retries, 1 by default
+    --min-delay <SECONDS>     The minimum delay between two requests to the same host
+    --max-rps <N>             The maximum number of requests per second to the same host
+    --max-connections-per-host <N>
+                              The maximum number of concurrent connections to the same host
+    --robots                  Skips the urls disallowed by the robots.txt of the host
+    --robots-file <FILE>      Checks the urls with the local robots.txt instead of the ones of the hosts
+    --encoding <LABEL>        Decodes all the documents with the encoding, e.g. Shift_JIS, instead of the detected one
+    --max-body-size <BYTES>   The maximum size of a response, 16777216 by default
+    --content-types <FORMAT=TYPE,...>
+                              The content types accepted for the html or text documents, e.g. html=text/html,text/plain
+    --allow-host <PATTERN>    Only fetches the documents from the host, e.g. *.statcan.gc.ca, can be repeated
+    --deny-private-ips        Refuses the hosts at private, loopback or link-local addresses
+    --max-fetches <N>         The maximum number of documents fetched by the render
+    --max-loop-iterations <N> The maximum number of iterations of all the loops
+    --max-nesting-depth <N>   The maximum number of loops nested in each other
+    --max-output-bytes <N>    The maximum size of the output
+    --time-budget <SECONDS>   The maximum time of the render
+    --cache-dir <DIR>         Caches the responses of the GET requests in the directory
+    --cache-ttl <SECONDS>     How long a cached response is used before it is revalidated, 3600 by default
+    --refresh                 Fetches the documents again, and updates the cache with them
+    --concurrency <N>         How many iterations of a loop fetching documents are rendered at the same time, 4 by default";
+
+/// What the command does with the template.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub enum Command {
+    #[default]
+    Render,
+    /// Checks the template in the file.
+    Check(PathBuf),
+    /// Prints the tree of the template in the file.
+    Ast(PathBuf),
+    /// Tries the selectors and the snippets against the page of the url or the file.
+    Repl(String),
+    /// Suggests the selector of the examples in the page of the url or the file.
+    Suggest(String)
+}
+
+/// The options given by the command line.
+#[derive(Debug,Default)]
+pub struct Cli {
+    pub command: Command,
+    /// The template to render, which is empty for the commands taking a file.
+    pub template: String,
+    pub output: Option<PathBuf>,
+    /// Where to write the provenance of the values of the output.
+    pub provenance: Option<PathBuf>,
+    pub request: RequestOptions,
+    pub cookie_jar: Option<PathBuf>,
+    pub politeness: PolitenessOptions,
+    pub limits: FetchLimits,
+    pub sandbox: SandboxPolicy,
+    pub concurrency: Option<usize>,
+    pub on_error: OnError,
+    pub plan: bool,
+    pub trace: bool,
+    /// Whether to print the tree of `ast` as JSON.
+    pub json: bool,
+    /// The texts of the page that `suggest` looks for.
+    pub examples: Vec<String>,
+    pub cache_dir: Option<PathBuf>,
+    pub cache_ttl: Option<Duration>,
+    pub refresh: bool
+}
+
+impl Cli {
+    pub fn cache_options(&self) -> Option<CacheOptions> {
+        self.cache_dir.as_ref().map(|dir| CacheOptions{
+            dir: dir.clone(),
+            ttl: self.cache_ttl.unwrap_or(DEFAULT_CACHE_TTL),
+            refresh: self.refresh
+        })
+    }
+}
+
+pub fn parse_args(args: impl Iterator<Item = String>) -> Result<Cli, ParseError> {
+    let mut cli = Cli::default();
+    let mut command = None;
+    let mut template = None;
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if command.is_none() && template.is_none() && ["check", "ast", "repl", "suggest"].contains(&arg.as_str()) {
+                command = Some(arg);
+                continue;
+            }
+            if template.is_some() {
+                return Err(ParseError::new_str(format!("unexpected argument: {}", arg)));
+            }
+            template = Some(arg);
+            continue;
+        }
+        match arg.as_str() {
+            "--robots" => {
+                cli.politeness.robots = true;
+                continue;
+            },
+            "--plan" => {
+                cli.plan = true;
+                continue;
+            },
+            "--trace" => {
+                cli.trace = true;
+                continue;
+            },
+            "--json" => {
+                cli.json = true;
+                continue;
+            },
+            "--refresh" => {
+                cli.refresh = true;
+                continue;
+            },
+            "--deny-private-ips" => {
+                cli.sandbox.deny_private_ips = true;
+                continue;
+            },
+            _ => {}
+        }
+        // Both of `--name value` and `--name=value` are accepted.
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) => (String::from(name), Some(String::from(value))),
+            None => (arg.clone(), None)
+        };
+        let mut value = || inline_value.clone().or_else(|| args.next()).ok_or_else(|| ParseError::new_str(format!("missing the value of the option: {}", name)));
+        match name.as_str() {
+            "--header" => {
+                let header = value()?;
+                match header.split_once(':') {
+                    Some((header_name, header_value)) => cli.request.headers.push((String::from(header_name.trim()), String::from(header_value.trim()))),
+                    None => return Err(ParseError::new_str(format!("invalid header, expect 'NAME: VALUE': {}", header)))
+                }
+            },
+            "--user-agent" => {
+                let user_agent = value()?;
+                cli.request.headers.push((String::from("user-agent"), user_agent));
+            },
+            "--cookie-jar" => cli.cookie_jar = Some(PathBuf::from(value()?)),
+            "--timeout" => cli.request.timeout = Some(seconds(&name, &value()?)?),
+            "--retries" => {
+                let retries = value()?;
+                cli.request.retries = Some(retries.parse().map_err(|_| ParseError::new_str(format!("{} must be a non-negative number: {}", name, retries)))?);
+            },
+            "--retry-delay" => cli.request.retry_delay = Some(seconds(&name, &value()?)?),
+            "--min-delay" => cli.politeness.min_delay = Some(seconds(&name, &value()?)?),
+            "--max-rps" => {
+                let max_rps = value()?;
+                cli.politeness.max_requests_per_second = Some(max_rps.parse().ok().filter(|x: &f64| *x > 0.0)
+                    .ok_or_else(|| ParseError::new_str(format!("{} must be a positive number: {}", name, max_rps)))?);
+            },
+            "--max-connections-per-host" => {
+                let max_connections = value()?;
+                cli.politeness.max_connections_per_host = Some(max_connections.parse().ok().filter(|x: &usize| *x > 0)
+                    .ok_or_else(|| ParseError::new_str(format!("{} must be a positive number: {}", name, max_connections)))?);
+            },
+            "--encoding" => {
+                let encoding = value()?;
+                charset::encoding_for_label(&encoding).map_err(|e| ParseError::new_str(e.to_string()))?;
+                cli.request.encoding = Some(encoding);
+            },
+            "--max-body-size" => {
+                let max_body_size = value()?;
+                cli.limits.max_body_size = max_body_size.parse().map_err(|_| ParseError::new_str(format!("{} must be a non-negative number of bytes: {}", name, max_body_size)))?;
+            },
+            "--content-types" => {
+                let content_types = value()?;
+                let (format, types) = content_types.split_once('=')
+                    .ok_or_else(|| ParseError::new_str(format!("invalid content types, expect 'FORMAT=TYPE,...': {}", content_types)))?;
+                let format = format.trim().parse().map_err(ParseError::new_str)?;
+                cli.limits.set_content_types(format, types.split(',').map(|x| String::from(x.trim())).filter(|x| !x.is_empty()).collect());
+            },
+            "--output" => cli.output = Some(PathBuf::from(value()?)),
+            "--allow-host" => cli.sandbox.allowed_hosts.push(value()?),
+            "--example" => cli.examples.push(value()?),
+            "--max-fetches" => cli.sandbox.max_fetches = Some(count(&name, &value()?)?),
+            "--max-loop-iterations" => cli.sandbox.max_loop_iterations = Some(count(&name, &value()?)?),
+            "--max-nesting-depth" => cli.sandbox.max_nesting_depth = Some(count(&name, &value()?)?),
+            "--max-output-bytes" => cli.sandbox.max_output_bytes = Some(count(&name, &value()?)?),
+            "--time-budget" => cli.sandbox.time_budget = Some(seconds(&name, &value()?)?),
+            "--provenance" => cli.provenance = Some(PathBuf::from(value()?)),
+            "--cache-dir" => cli.cache_dir = Some(PathBuf::from(value()?)),
+            "--cache-ttl" => cli.cache_ttl = Some(seconds(&name, &value()?)?),
+            "--concurrency" => {
+                let concurrency = value()?;
+                cli.concurrency = Some(concurrency.parse().ok().filter(|x: &usize| *x > 0)
+                    .ok_or_else(|| ParseError::new_str(format!("{} must be a positive number: {}", name, concurrency)))?);
+            },
+            "--on-error" => cli.on_error = value()?.parse().map_err(ParseError::new_str)?,
+            "--robots-file" => {
+                cli.politeness.robots = true;
+                cli.politeness.robots_file = Some(PathBuf::from(value()?));
+            },
+            _ => return Err(ParseError::new_str(format!("unknown option: {}", name)))
+        }
+    }
+    match command.as_deref() {
+        Some("check") => cli.command = Command::Check(PathBuf::from(template.ok_or_else(|| ParseError::new("Expect the file of the template to check"))?)),
+        Some("ast") => cli.command = Command::Ast(PathBuf::from(template.ok_or_else(|| ParseError::new("Expect the file of the template"))?)),
+        Some("repl") => cli.command = Command::Repl(template.ok_or_else(|| ParseError::new("Expect the url or the file of the page"))?),
+        Some("suggest") => {
+            cli.command = Command::Suggest(template.ok_or_else(|| ParseError::new("Expect the url or the file of the page"))?);
+            if cli.examples.is_empty() {
+                return Err(ParseError::new("Expect at least one --example"));
+            }
+        },
+        _ => cli.template = template.ok_or_else(|| ParseError::new("Expect template as a parameter"))?
+    }
+    Ok(cli)
+}
+
+fn count(name: &str, value: &str) -> Result<usize, ParseError> {
+    value.parse().map_err(|_| ParseError::new_str(format!("{} must be a non-negative number: {}", name, value)))
+}
+
+fn seconds(name: &str, value: &str) -> Result<Duration, ParseError> {
+    value.parse::<f64>().ok().and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| ParseError::new_str(format!("{} must be a non-negative number of seconds: {}", name, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, Command};
+    use crate::fetch::limits::DocFormat;
+    use crate::template_parser::OnError;
+    use crate::template_parser::sandbox::SandboxPolicy;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter().map(|x| String::from(*x)).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let cli = parse_args(args(&["--header", "Accept-Language: fr", "--user-agent=Mozilla/5.0", "{{var x}}", "--cookie-jar", "cookies.txt"])).unwrap();
+        assert_eq!(cli.template, "{{var x}}");
+        assert_eq!(cli.request.headers, vec![(String::from("Accept-Language"), String::from("fr")), (String::from("user-agent"), String::from("Mozilla/5.0"))]);
+        assert_eq!(cli.cookie_jar, Some(PathBuf::from("cookies.txt")));
+        assert!(parse_args(args(&["--header", "Accept"])).is_err());
+        assert!(parse_args(args(&["--unknown", "x"])).is_err());
+        assert!(parse_args(args(&["--header"])).is_err());
+        assert!(parse_args(args(&[])).is_err());
+    }
+
+    #[test]
+    fn test_parse_retries() {
+        let cli = parse_args(args(&["--timeout", "2.5", "--retries=4", "--retry-delay", "0.5", "{{var x}}"])).unwrap();
+        assert_eq!(cli.request.timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(cli.request.retries, Some(4));
+        assert_eq!(cli.request.retry_delay, Some(Duration::from_millis(500)));
+        assert!(parse_args(args(&["--timeout", "-1", "{{var x}}"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_politeness() {
+        let cli = parse_args(args(&["--min-delay", "2", "--max-rps=0.5", "--max-connections-per-host", "2", "--robots", "{{var x}}"])).unwrap();
+        assert_eq!(cli.politeness.min_delay, Some(Duration::from_secs(2)));
+        assert_eq!(cli.politeness.max_requests_per_second, Some(0.5));
+        assert_eq!(cli.politeness.max_connections_per_host, Some(2));
+        assert!(cli.politeness.robots);
+        let cli = parse_args(args(&["--robots-file", "robots.txt", "{{var x}}"])).unwrap();
+        assert!(cli.politeness.robots);
+        assert_eq!(cli.politeness.robots_file, Some(PathBuf::from("robots.txt")));
+        assert!(parse_args(args(&["--max-rps", "0", "{{var x}}"])).is_err());
+        assert!(parse_args(args(&["--max-connections-per-host", "0", "{{var x}}"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_concurrency() {
+        assert_eq!(parse_args(args(&["--concurrency", "8", "{{var x}}"])).unwrap().concurrency, Some(8));
+        assert!(parse_args(args(&["--concurrency", "0", "{{var x}}"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_plan() {
+        assert!(parse_args(args(&["--plan", "{{var x}}"])).unwrap().plan);
+    }
+
+    #[test]
+    fn test_parse_cache() {
+        assert_eq!(parse_args(args(&["--refresh", "{{var x}}"])).unwrap().cache_options(), None);
+        let cache_options = parse_args(args(&["--cache-dir", "cache", "--cache-ttl=60", "--refresh", "{{var x}}"])).unwrap().cache_options().unwrap();
+        assert_eq!(cache_options.dir, PathBuf::from("cache"));
+        assert_eq!(cache_options.ttl, Duration::from_secs(60));
+        assert!(cache_options.refresh);
+    }
+
+    #[test]
+    fn test_parse_encoding() {
+        assert_eq!(parse_args(args(&["--encoding", "Shift_JIS", "{{var x}}"])).unwrap().request.encoding, Some(String::from("Shift_JIS")));
+        assert!(parse_args(args(&["--encoding", "klingon", "{{var x}}"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let cli = parse_args(args(&["--max-body-size", "1024", "--content-types", "html=text/html, text/plain", "{{var x}}"])).unwrap();
+        assert_eq!(cli.limits.max_body_size, 1024);
+        assert!(cli.limits.content_types.contains(&(DocFormat::Html, vec![String::from("text/html"), String::from("text/plain")])));
+        assert!(parse_args(args(&["--content-types", "pdf=application/pdf", "{{var x}}"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_sandbox() {
+        let cli = parse_args(args(&["--allow-host", "*.statcan.gc.ca", "--allow-host=canada.ca", "--deny-private-ips", "--max-fetches", "100", "--max-loop-iterations", "1000",
+            "--max-nesting-depth", "3", "--max-output-bytes", "65536", "--time-budget", "60", "{{var x}}"])).unwrap();
+        assert_eq!(cli.sandbox, SandboxPolicy{
+            allowed_hosts: vec![String::from("*.statcan.gc.ca"), String::from("canada.ca")],
+            deny_private_ips: true,
+            max_fetches: Some(100),
+            max_loop_iterations: Some(1000),
+            max_nesting_depth: Some(3),
+            max_output_bytes: Some(65536),
+            time_budget: Some(Duration::from_secs(60))
+        });
+        assert!(parse_args(args(&["--max-fetches", "-1", "{{var x}}"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_output() {
+        assert_eq!(parse_args(args(&["--output", "out.json", "{{var x}}"])).unwrap().output, Some(PathBuf::from("out.json")));
+    }
+
+    #[test]
+    fn test_parse_on_error() {
+        assert_eq!(parse_args(args(&["--on-error", "placeholder", "{{var x}}"])).unwrap().on_error, OnError::Placeholder);
+        assert!(parse_args(args(&["--on-error=retry", "{{var x}}"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_check() {
+        assert_eq!(parse_args(args(&["check", "--max-nesting-depth", "2", "template.tpl"])).unwrap().command, Command::Check(PathBuf::from("template.tpl")));
+        assert!(parse_args(args(&["check"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_ast() {
+        let cli = parse_args(args(&["ast", "template.tpl", "--json"])).unwrap();
+        assert_eq!(cli.command, Command::Ast(PathBuf::from("template.tpl")));
+        assert!(cli.json);
+    }
+
+    #[test]
+    fn test_parse_trace() {
+        assert!(parse_args(args(&["--trace", "{{var x}}"])).unwrap().trace);
+    }
+
+    #[test]
+    fn test_parse_provenance() {
+        assert_eq!(parse_args(args(&["--provenance=out.jsonl", "{{var x}}"])).unwrap().provenance, Some(PathBuf::from("out.jsonl")));
+    }
+
+    #[test]
+    fn test_parse_repl() {
+        assert_eq!(parse_args(args(&["repl", "https://www.statcan.gc.ca"])).unwrap().command, Command::Repl(String::from("https://www.statcan.gc.ca")));
+    }
+
+    #[test]
+    fn test_parse_suggest() {
+        let cli = parse_args(args(&["suggest", "page.html", "--example", "Alberta", "--example=Quebec"])).unwrap();
+        assert_eq!(cli.command, Command::Suggest(String::from("page.html")));
+        assert_eq!(cli.examples, vec![String::from("Alberta"), String::from("Quebec")]);
+        assert!(parse_args(args(&["suggest", "page.html"])).is_err());
+    }
+}
@@ -1,12 +1,16 @@
 pub mod css;
+pub mod json_path;
+pub mod regex;
 
-pub type CommonError = Box<dyn std::error::Error>;
+pub type CommonError = Box<dyn std::error::Error + Send + Sync>;
 
 pub trait Document: Clone {
     fn select(&self, selector: &str) -> Result<Option<String>, CommonError>;
     fn select_prop(&self, selector: &str, prop: &str) -> Result<Option<String>, CommonError>;
     fn select_all(&self, selector: &str) -> Result<Option<Vec<String>>, CommonError>; 
     fn select_all_prop(&self, selector: &str, prop: &str) -> Result<Option<Vec<String>>, CommonError>;
+    /// The property of each node matched by the selector, or `None` for a node without it.
+    fn select_each_prop(&self, selector: &str, prop: &str) -> Result<Vec<Option<String>>, CommonError>;
 }
 
 #[derive(Debug,Clone)]
@@ -1,4 +1,5 @@
-use scraper::{Selector, Html};
+use scraper::{Selector, Html, ElementRef};
+use serde_json::Value;
 use super::{Document, CommonError, ParseError};
 
 #[derive(Debug,Clone)]
@@ -6,9 +7,27 @@ pub struct CssDocument {
     doc: Html
 }
 
+/// The cells of a HTML table. The cells spanning several rows or columns are repeated in each of
+/// them, so that every row has a cell for each column.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Table {
+    /// The names of the columns, taken from the header rows, or `1`, `2`, ... if there is no header.
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>
+}
+
+/// A node selected by a selector, e.g. to show the matches of the selector in the REPL.
+#[derive(Debug,Clone,PartialEq)]
+pub struct NodeMatch {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    /// The text of the node and its descendants, with the whitespaces collapsed.
+    pub text: String
+}
+
 impl Document for CssDocument {
     fn select(&self, selector: &str) -> Result<Option<String>, CommonError> {
-        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) )?;
+        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
         match self.doc.select(&parsed_selector).next() {
             Some(node) => {let mut buff = String::new(); node.text().for_each(|x| buff.push_str(x)); Ok(Some(buff))},
             None => Ok(None)
@@ -16,23 +35,23 @@ impl Document for CssDocument {
     }
 
     fn select_prop(&self, selector: &str, prop: &str) -> Result<Option<String>, CommonError> {
-        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) )?;
+        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
         match self.doc.select(&parsed_selector).next() {
-            Some(node) => Ok(node.value().attr(prop).map(|x| String::from(x))),
+            Some(node) => Ok(node.value().attr(prop).map(String::from)),
             None => Ok(None)
         }
     }
 
     fn select_all(&self, selector: &str) -> Result<Option<Vec<String>>, CommonError> {
-        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) )?;
+        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
         let mut result = Vec::new();
-        let mut selected_node = self.doc.select(&parsed_selector);
-        while let Some(node) = selected_node.next() {
+        let selected_node = self.doc.select(&parsed_selector);
+        for node in selected_node {
             let mut buff = String::new();
             node.text().for_each(|x| buff.push_str(x));
             result.push(buff);
         }
-        if result.len() > 0 {
+        if !result.is_empty() {
             Ok(Some(result))
         } else {
             Ok(None)
@@ -40,20 +59,299 @@ impl Document for CssDocument {
     }
 
     fn select_all_prop(&self, selector: &str, prop: &str) -> Result<Option<Vec<String>>, CommonError> {
-        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) )?;
+        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
         let mut result = Vec::new();
-        let mut selected_node = self.doc.select(&parsed_selector);
-        while let Some(node) = selected_node.next() {
+        let selected_node = self.doc.select(&parsed_selector);
+        for node in selected_node {
             if let Some(attr_val) = node.value().attr(prop) {
                 result.push(String::from(attr_val));
             }
         }
-        if result.len() > 0 {
+        if !result.is_empty() {
             Ok(Some(result))
         } else {
             Ok(None)
         }
     }
+
+    fn select_each_prop(&self, selector: &str, prop: &str) -> Result<Vec<Option<String>>, CommonError> {
+        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
+        Ok(self.doc.select(&parsed_selector).map(|node| node.value().attr(prop).map(String::from)).collect())
+    }
+}
+
+impl CssDocument {
+    /// Returns the content of the OpenGraph or the named meta tag, e.g. `og:title` or `description`,
+    /// falling back to the microdata property `itemprop`. The microdata is looked up inside the
+    /// item of `item_type`(e.g. `Product`) if it is given.
+    pub fn meta(&self, name: &str, item_type: Option<&str>) -> Result<Option<String>, CommonError> {
+        if item_type.is_none() {
+            for attr in &["property", "name"] {
+                let meta = self.select_prop(&format!("meta[{}=\"{}\"]", attr, name), "content")?;
+                if meta.is_some() {
+                    return Ok(meta);
+                }
+            }
+        }
+        let item_selector = match item_type {
+            Some(item_type) => format!("[itemscope][itemtype$=\"/{}\"] [itemprop=\"{}\"]", item_type, name),
+            None => format!("[itemprop=\"{}\"]", name)
+        };
+        let parsed_selector = Selector::parse(&item_selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
+        match self.doc.select(&parsed_selector).next() {
+            Some(node) => {
+                let value = node.value();
+                match value.attr("content").or_else(|| value.attr("href")).or_else(|| value.attr("src")) {
+                    Some(content) => Ok(Some(String::from(content))),
+                    None => {let mut buff = String::new(); node.text().for_each(|x| buff.push_str(x)); Ok(Some(buff.trim().to_owned()))}
+                }
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Returns the JSON-LD objects of the `<script type="application/ld+json">` blocks, including
+    /// the ones listed in arrays and `@graph`. Only the objects of `item_type` are returned if it is given. The blocks that are not valid JSON are skipped.
+    pub fn json_ld(&self, item_type: Option<&str>) -> Result<Vec<Value>, CommonError> {
+        let parsed_selector = Selector::parse("script[type=\"application/ld+json\"]").map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
+        let mut result = Vec::new();
+        for node in self.doc.select(&parsed_selector) {
+            let mut buff = String::new();
+            node.text().for_each(|x| buff.push_str(x));
+            // A malformed block of the page does not hide the valid ones.
+            match serde_json::from_str(&buff) {
+                Ok(value) => Self::flatten_json_ld(value, &mut result),
+                Err(e) => log::trace!("json-ld block skipped: {}", e)
+            }
+        }
+        if let Some(item_type) = item_type {
+            result.retain(|value| match value.get("@type") {
+                Some(Value::String(t)) => t == item_type,
+                Some(Value::Array(types)) => types.iter().any(|t| t == item_type),
+                _ => false
+            });
+        }
+        Ok(result)
+    }
+
+    /// Returns all the nodes selected by the selector, with their attributes sorted by name.
+    pub fn matches(&self, selector: &str) -> Result<Vec<NodeMatch>, CommonError> {
+        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
+        Ok(self.doc.select(&parsed_selector).map(|node| {
+            let mut attributes: Vec<(String, String)> = node.value().attrs().map(|(name, value)| (String::from(name), String::from(value))).collect();
+            attributes.sort();
+            NodeMatch{
+                name: String::from(node.value().name()),
+                attributes,
+                text: node.text().collect::<Vec<_>>().join(" ").split_whitespace().collect::<Vec<_>>().join(" ")
+            }
+        }).collect())
+    }
+
+    /// Returns the first table selected by the selector. The rows in `<thead>` and the leading
+    /// rows of `<th>` only are taken as the header rows.
+    pub fn table(&self, selector: &str) -> Result<Option<Table>, CommonError> {
+        let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
+        let table = match self.doc.select(&parsed_selector).next() {
+            Some(table) => table,
+            None => return Ok(None)
+        };
+        let tr_selector = Selector::parse("tr").map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
+        let mut header_rows: Vec<Vec<String>> = Vec::new();
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        // The cells spanning down to the next rows: the number of rows left and the text, per column.
+        let mut spanned: Vec<Option<(usize, String)>> = Vec::new();
+        for tr in table.select(&tr_selector) {
+            let owner_table = tr.ancestors().filter_map(ElementRef::wrap).find(|e| e.value().name() == "table");
+            if owner_table != Some(table) {
+                continue;
+            }
+            let in_thead = tr.parent().and_then(ElementRef::wrap).map(|e| e.value().name() == "thead").unwrap_or(false);
+            let mut row = Vec::new();
+            let mut all_th = true;
+            for cell in tr.children().filter_map(ElementRef::wrap).filter(|e| e.value().name() == "th" || e.value().name() == "td") {
+                while let Some(text) = Self::take_spanned(&mut spanned, row.len()) {
+                    row.push(text);
+                }
+                all_th &= cell.value().name() == "th";
+                let text = cell.text().collect::<Vec<_>>().join(" ").split_whitespace().collect::<Vec<_>>().join(" ");
+                let span = |name: &str| cell.value().attr(name).and_then(|x| x.trim().parse::<usize>().ok()).unwrap_or(1).clamp(1, 1000);
+                let rowspan = span("rowspan");
+                for _ in 0..span("colspan") {
+                    if rowspan > 1 {
+                        if spanned.len() <= row.len() {
+                            spanned.resize(row.len() + 1, None);
+                        }
+                        spanned[row.len()] = Some((rowspan - 1, text.clone()));
+                    }
+                    row.push(text.clone());
+                }
+            }
+            for column in row.len()..spanned.len() {
+                if let Some(text) = Self::take_spanned(&mut spanned, column) {
+                    row.resize(column, String::new());
+                    row.push(text);
+                }
+            }
+            if row.is_empty() {
+                continue;
+            }
+            if in_thead || (rows.is_empty() && all_th) {
+                header_rows.push(row);
+            } else {
+                rows.push(row);
+            }
+        }
+
+        let width = header_rows.iter().chain(rows.iter()).map(|row| row.len()).max().unwrap_or(0);
+        let columns = (0..width).map(|column| {
+            let mut names: Vec<&str> = Vec::new();
+            for row in &header_rows {
+                if let Some(name) = row.get(column) {
+                    if !name.is_empty() && !names.contains(&name.as_str()) {
+                        names.push(name);
+                    }
+                }
+            }
+            if names.is_empty() {
+                (column + 1).to_string()
+            } else {
+                names.join(" ")
+            }
+        }).collect();
+        for row in rows.iter_mut() {
+            row.resize(width, String::new());
+        }
+        Ok(Some(Table{columns, rows}))
+    }
+
+    /// Suggests the selector of the nodes like the examples, e.g. `div.field-item > ul > li > a`
+    /// for `Alberta` and `Quebec`. Each example is the deepest node whose text contains it, preferring
+    /// the one whose text is exactly it. The steps from the common ancestor of the examples down to
+    /// them keep the tags and the classes they share, then the ancestors are added up to the one with
+    /// an id, and dropped again from the top as long as the selector matches the same nodes.
+    pub fn suggest_selector(&self, examples: &[String]) -> Result<String, CommonError> {
+        let mut paths: Vec<Vec<ElementRef>> = Vec::new();
+        for example in examples {
+            let element = self.find_example(example)
+                .ok_or_else(|| Box::new(ParseError::new_str(format!("unable to find the example '{}' in the document", example))))?;
+            let mut path: Vec<ElementRef> = element.ancestors().filter_map(ElementRef::wrap).collect();
+            path.reverse();
+            path.push(element);
+            paths.push(path);
+        }
+        let shortest = paths.iter().map(Vec::len).min().unwrap_or(0);
+        if shortest == 0 {
+            return Err(Box::new(ParseError::new("at least one example is required")));
+        }
+        // The common ancestor is kept above all the examples, even if an example contains another.
+        let common = (0..shortest - 1).take_while(|&depth| paths.iter().all(|path| path[depth] == paths[0][depth])).count();
+        let relatives: Vec<&[ElementRef]> = paths.iter().map(|path| &path[common..]).collect();
+        let same_shape = relatives.iter().all(|relative| relative.len() == relatives[0].len()
+            && relative.iter().zip(relatives[0].iter()).all(|(x, y)| x.value().name() == y.value().name()));
+        let (combinator, relative) = if same_shape {
+            let steps: Vec<String> = (0..relatives[0].len()).map(|depth| {
+                Self::shared_step(&relatives.iter().map(|relative| relative[depth]).collect::<Vec<_>>())
+            }).collect();
+            (" > ", steps.join(" > "))
+        } else {
+            (" ", Self::shared_step(&relatives.iter().map(|relative| relative[relative.len() - 1]).collect::<Vec<_>>()))
+        };
+        if relative == "*" {
+            return Err(Box::new(ParseError::new("the examples have no tag or class in common")));
+        }
+
+        // The ancestors from the common one up to the one with an id, or to `body`.
+        let mut ancestors: Vec<String> = Vec::new();
+        for element in paths[0][..common].iter().rev() {
+            let name = element.value().name();
+            if name == "html" {
+                break;
+            }
+            let id = element.value().id().filter(|x| Self::is_identifier(x));
+            ancestors.push(match id {
+                Some(id) => format!("{}#{}", name, id),
+                None => Self::shared_step(&[*element])
+            });
+            if id.is_some() || name == "body" {
+                break;
+            }
+        }
+        let selector_with = |count: usize| match count {
+            0 => relative.clone(),
+            _ => format!("{}{}{}", ancestors[..count].iter().rev().map(String::as_str).collect::<Vec<_>>().join(" > "), combinator, relative)
+        };
+        let count_matches = |selector: &str| -> Result<usize, CommonError> {
+            let parsed_selector = Selector::parse(selector).map_err(|e| Box::new(ParseError::new_str(format!("[CSS Parse Error]: {:?}", e))) as CommonError)?;
+            Ok(self.doc.select(&parsed_selector).count())
+        };
+        let fewest = count_matches(&selector_with(ancestors.len()))?;
+        for count in 0..ancestors.len() {
+            let selector = selector_with(count);
+            if count_matches(&selector)? == fewest {
+                return Ok(selector);
+            }
+        }
+        Ok(selector_with(ancestors.len()))
+    }
+
+    /// Returns the deepest element whose text contains the example, preferring the one whose text
+    /// is exactly the example.
+    fn find_example(&self, example: &str) -> Option<ElementRef<'_>> {
+        let example = example.split_whitespace().collect::<Vec<_>>().join(" ");
+        let text_of = |element: &ElementRef| element.text().collect::<Vec<_>>().join(" ").split_whitespace().collect::<Vec<_>>().join(" ");
+        let deepest: Vec<(ElementRef, String)> = self.doc.root_element().descendants().filter_map(ElementRef::wrap)
+            .filter(|element| !["script", "style", "noscript", "template"].contains(&element.value().name()))
+            .map(|element| (element, text_of(&element)))
+            .filter(|(element, text)| text.contains(&example) && !element.children().filter_map(ElementRef::wrap).any(|child| text_of(&child).contains(&example)))
+            .collect();
+        deepest.iter().find(|(_, text)| *text == example).or_else(|| deepest.first()).map(|(element, _)| *element)
+    }
+
+    /// The tag of the elements with the classes they all have in order, or `*` if their tags differ.
+    fn shared_step(elements: &[ElementRef]) -> String {
+        let name = elements[0].value().name();
+        let mut step = String::from(if elements.iter().all(|x| x.value().name() == name) { name } else { "*" });
+        let mut classes: Vec<&str> = elements[0].value().classes().filter(|x| Self::is_identifier(x)).collect();
+        classes.sort_unstable();
+        for class in classes {
+            if elements.iter().all(|x| x.value().classes().any(|other| other == class)) {
+                step = format!("{}.{}", step.trim_start_matches('*'), class);
+            }
+        }
+        if step.is_empty() { String::from("*") } else { step }
+    }
+
+    /// Whether the name can be used in a selector as it is.
+    fn is_identifier(name: &str) -> bool {
+        let mut chars = name.chars();
+        chars.next().map(|x| x.is_ascii_alphabetic() || x == '_' || x == '-').unwrap_or(false)
+            && chars.all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-')
+    }
+
+    fn take_spanned(spanned: &mut [Option<(usize, String)>], column: usize) -> Option<String> {
+        let cell = spanned.get_mut(column)?;
+        let (rows_left, text) = cell.take()?;
+        if rows_left > 1 {
+            *cell = Some((rows_left - 1, text.clone()));
+        }
+        Some(text)
+    }
+
+    fn flatten_json_ld(value: Value, result: &mut Vec<Value>) {
+        match value {
+            Value::Array(values) => values.into_iter().for_each(|value| Self::flatten_json_ld(value, result)),
+            Value::Object(mut map) => {
+                if let Some(graph) = map.remove("@graph") {
+                    Self::flatten_json_ld(graph, result);
+                }
+                if map.keys().any(|key| key != "@context") {
+                    result.push(Value::Object(map));
+                }
+            },
+            _ => {}
+        }
+    }
 }
 
 impl std::convert::From<&str> for CssDocument {
@@ -63,3 +361,87 @@ impl std::convert::From<&str> for CssDocument {
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{CssDocument, Document, NodeMatch};
+
+    #[test]
+    fn test_select() {
+        let doc = CssDocument::from("<div><a class='name' href='/alberta'>Alberta</a><a class='name' href='/quebec'>Quebec</a></div>");
+        assert_eq!(doc.select("a.name").unwrap(), Some(String::from("Alberta")));
+        assert_eq!(doc.select_prop("a.name", "href").unwrap(), Some(String::from("/alberta")));
+        assert_eq!(doc.select_all("a.name").unwrap(), Some(vec![String::from("Alberta"), String::from("Quebec")]));
+        assert_eq!(doc.select_all_prop("a.name", "href").unwrap(), Some(vec![String::from("/alberta"), String::from("/quebec")]));
+        assert_eq!(doc.select_each_prop("div, a", "href").unwrap(), vec![None, Some(String::from("/alberta")), Some(String::from("/quebec"))]);
+        assert_eq!(doc.select("p").unwrap(), None);
+        assert!(doc.select("a[").is_err());
+        assert_eq!(doc.matches("a.name").unwrap()[1], NodeMatch{
+            name: String::from("a"),
+            attributes: vec![(String::from("class"), String::from("name")), (String::from("href"), String::from("/quebec"))],
+            text: String::from("Quebec")
+        });
+    }
+
+    #[test]
+    fn test_meta() {
+        let doc = CssDocument::from(r#"<head><meta property="og:title" content="A Pen"><meta name="description" content="Blue"></head>
+            <div itemscope itemtype="https://schema.org/Product"><span itemprop="name">Pen</span><meta itemprop="price" content="12.99"></div>"#);
+        assert_eq!(doc.meta("og:title", None).unwrap(), Some(String::from("A Pen")));
+        assert_eq!(doc.meta("description", None).unwrap(), Some(String::from("Blue")));
+        assert_eq!(doc.meta("name", None).unwrap(), Some(String::from("Pen")));
+        assert_eq!(doc.meta("price", Some("Product")).unwrap(), Some(String::from("12.99")));
+        assert_eq!(doc.meta("price", Some("Offer")).unwrap(), None);
+        assert_eq!(doc.meta("og:image", None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_table() {
+        let doc = CssDocument::from(r#"<table class="stats">
+            <thead><tr><th rowspan="2">Province</th><th colspan="2">Population</th></tr><tr><th>2016</th><th>2021</th></tr></thead>
+            <tbody>
+                <tr><td>Alberta</td><td>4,067,175</td><td>4,262,635</td></tr>
+                <tr><td rowspan="2">Other</td><td colspan="2">N/A <table><tr><td>nested</td></tr></table></td></tr>
+                <tr><td>1</td></tr>
+            </tbody></table>"#);
+        let table = doc.table("table.stats").unwrap().unwrap();
+        assert_eq!(table.columns, vec!["Province", "Population 2016", "Population 2021"]);
+        assert_eq!(table.rows, vec![
+            vec!["Alberta", "4,067,175", "4,262,635"],
+            vec!["Other", "N/A nested", "N/A nested"],
+            vec!["Other", "1", ""]
+        ]);
+        assert_eq!(doc.table("table.missing").unwrap(), None);
+
+        let doc = CssDocument::from("<table><tr><td>a</td><td>b</td></tr></table>");
+        let table = doc.table("table").unwrap().unwrap();
+        assert_eq!(table.columns, vec!["1", "2"]);
+        assert_eq!(table.rows, vec![vec!["a", "b"]]);
+    }
+
+    #[test]
+    fn test_json_ld() {
+        let doc = CssDocument::from(r#"<script type="application/ld+json">{"@context": "https://schema.org", "@graph": [{"@type": "Product", "name": "Pen"}, {"@type": "Organization", "name": "ACME"}]}</script>
+            <script type="application/ld+json">[{"@type": ["Thing", "Product"], "name": "Ink"}]</script>"#);
+        assert_eq!(doc.json_ld(None).unwrap().len(), 3);
+        let products = doc.json_ld(Some("Product")).unwrap();
+        assert_eq!(products.len(), 2);
+        assert_eq!(products[1]["name"], "Ink");
+        let doc = CssDocument::from(r#"<script type="application/ld+json">{"@type": "Product",</script>
+            <script type="application/ld+json">{"@type": "Product", "name": "Pen"}</script>"#);
+        let products = doc.json_ld(Some("Product")).unwrap();
+        assert_eq!(products.len(), 1);
+        assert_eq!(products[0]["name"], "Pen");
+    }
+
+    #[test]
+    fn test_suggest_selector() {
+        let doc = CssDocument::from(r#"<div id="main"><table><tr class="odd"><td>Province</td><td class="name">Alberta</td></tr><tr class="even"><td>Province</td><td class="name">Quebec</td></tr></table>
+            <p>Ontario is big</p><p><b>Ontario</b></p><span class="name x">Yukon</span></div><div><span class="name">Nunavut</span></div>"#);
+        let examples = |examples: &[&str]| examples.iter().map(|x| String::from(*x)).collect::<Vec<_>>();
+        assert_eq!(doc.suggest_selector(&examples(&["Alberta", "Quebec"])).unwrap(), "tr > td.name");
+        assert_eq!(doc.suggest_selector(&examples(&["Ontario"])).unwrap(), "b");
+        assert_eq!(doc.suggest_selector(&examples(&["Alberta", "Yukon"])).unwrap(), "div#main .name");
+        assert_eq!(doc.suggest_selector(&examples(&["Yukon", "Nunavut"])).unwrap(), "div > span.name");
+        assert!(doc.suggest_selector(&examples(&["Manitoba"])).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use serde_json::Value;
+use super::{CommonError, ParseError};
+
+/// A step of the JSON path, e.g. `$.offers[0].price` has 3 steps: `offers`, `0` and `price`.
+#[derive(Debug,Clone,PartialEq)]
+enum Step {
+    Key(String),
+    Index(usize),
+    Wildcard
+}
+
+/// Selects the values from the JSON value by a subset of JSONPath: the root `$`, the children
+/// `.key` or `['key']`, the array index `[0]`, and the wildcard `.*` or `[*]`.
+pub fn select<'a>(value: &'a Value, path: &str) -> Result<Vec<&'a Value>, CommonError> {
+    let mut selected = vec![value];
+    for step in parse(path)? {
+        selected = selected.into_iter().flat_map(|value| match (&step, value) {
+            (Step::Key(key), Value::Object(map)) => map.get(key).into_iter().collect(),
+            (Step::Index(index), Value::Array(array)) => array.get(*index).into_iter().collect(),
+            (Step::Wildcard, Value::Object(map)) => map.values().collect(),
+            (Step::Wildcard, Value::Array(array)) => array.iter().collect(),
+            _ => Vec::new()
+        }).collect();
+    }
+    Ok(selected)
+}
+
+/// Renders the JSON value as the text of the output: strings without quotes, others as JSON.
+pub fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        _ => value.to_string()
+    }
+}
+
+fn parse(path: &str) -> Result<Vec<Step>, CommonError> {
+    let error = || Box::new(ParseError::new_str(format!("[JSON Path Error]: invalid path: {}", path))) as CommonError;
+    let rest = path.trim().strip_prefix('$').ok_or_else(error)?;
+    let mut steps = Vec::new();
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => {
+                let mut key = String::new();
+                while let Some(c) = chars.next_if(|c| *c != '.' && *c != '[') {
+                    key.push(c);
+                }
+                match key.as_str() {
+                    "" => return Err(error()),
+                    "*" => steps.push(Step::Wildcard),
+                    _ => steps.push(Step::Key(key))
+                }
+            },
+            '[' => {
+                let mut inner = String::new();
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c) => inner.push(c),
+                        None => return Err(error())
+                    }
+                }
+                let inner = inner.trim();
+                if inner == "*" {
+                    steps.push(Step::Wildcard);
+                } else if let Ok(index) = inner.parse::<usize>() {
+                    steps.push(Step::Index(index));
+                } else if inner.len() >= 2 && (inner.starts_with('\'') && inner.ends_with('\'') || inner.starts_with('"') && inner.ends_with('"')) {
+                    steps.push(Step::Key(String::from(&inner[1..inner.len()-1])));
+                } else {
+                    return Err(error());
+                }
+            },
+            _ => return Err(error())
+        }
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{select, to_text, parse, Step};
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("$.offers[0]['price'].*[*]").unwrap(), vec![Step::Key(String::from("offers")), Step::Index(0), Step::Key(String::from("price")), Step::Wildcard, Step::Wildcard]);
+        assert_eq!(parse("$").unwrap(), vec![]);
+        assert!(parse("offers").is_err());
+        assert!(parse("$.").is_err());
+        assert!(parse("$[0").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let value = json!({"name": "Pen", "offers": [{"price": 12.99}, {"price": "13.5"}]});
+        assert_eq!(select(&value, "$.name").unwrap().into_iter().map(to_text).collect::<Vec<_>>(), vec!["Pen"]);
+        assert_eq!(select(&value, "$.offers[1].price").unwrap().into_iter().map(to_text).collect::<Vec<_>>(), vec!["13.5"]);
+        assert_eq!(select(&value, "$.offers[*].price").unwrap().into_iter().map(to_text).collect::<Vec<_>>(), vec!["12.99", "13.5"]);
+        assert!(select(&value, "$.offers.price").unwrap().is_empty());
+    }
+}
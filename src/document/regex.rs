@@ -0,0 +1,106 @@
+use regex::Regex;
+use std::collections::HashMap;
+use super::{Document, CommonError, ParseError};
+
+/// The value of a capture group, with the named capture groups of the same match.
+pub type Captures = (String, HashMap<String,String>);
+
+/// The document that selects the values from the raw text by regular expressions. The selector is
+/// the pattern, and the property is the name or the index of a capture group.
+#[derive(Debug,Clone)]
+pub struct RegexDocument {
+    text: String
+}
+
+impl Document for RegexDocument {
+    fn select(&self, selector: &str) -> Result<Option<String>, CommonError> {
+        self.select_prop(selector, "0")
+    }
+
+    fn select_prop(&self, selector: &str, prop: &str) -> Result<Option<String>, CommonError> {
+        let pattern = Self::compile(selector)?;
+        match pattern.captures(&self.text) {
+            Some(captures) => Ok(Self::group(&captures, prop)),
+            None => Ok(None)
+        }
+    }
+
+    fn select_all(&self, selector: &str) -> Result<Option<Vec<String>>, CommonError> {
+        self.select_all_prop(selector, "0")
+    }
+
+    fn select_all_prop(&self, selector: &str, prop: &str) -> Result<Option<Vec<String>>, CommonError> {
+        let pattern = Self::compile(selector)?;
+        let result: Vec<String> = pattern.captures_iter(&self.text).filter_map(|captures| Self::group(&captures, prop)).collect();
+        if !result.is_empty() {
+            Ok(Some(result))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn select_each_prop(&self, selector: &str, prop: &str) -> Result<Vec<Option<String>>, CommonError> {
+        let pattern = Self::compile(selector)?;
+        Ok(pattern.captures_iter(&self.text).map(|captures| Self::group(&captures, prop)).collect())
+    }
+}
+
+impl RegexDocument {
+    /// Returns the value of the capture group `prop` of every match, together with all the named
+    /// capture groups of the match keyed by the group name.
+    pub fn select_all_captures(&self, selector: &str, prop: &str) -> Result<Vec<Captures>, CommonError> {
+        let pattern = Self::compile(selector)?;
+        let names: Vec<&str> = pattern.capture_names().flatten().collect();
+        Ok(pattern.captures_iter(&self.text).filter_map(|captures| {
+            let named = names.iter()
+                .filter_map(|name| captures.name(name).map(|value| (String::from(*name), String::from(value.as_str()))))
+                .collect();
+            Self::group(&captures, prop).map(|value| (value, named))
+        }).collect())
+    }
+
+    fn compile(selector: &str) -> Result<Regex, CommonError> {
+        Regex::new(selector).map_err(|e| Box::new(ParseError::new_str(format!("[Regex Parse Error]: {}", e))) as CommonError)
+    }
+
+    fn group(captures: &regex::Captures, prop: &str) -> Option<String> {
+        let group = match prop.parse::<usize>() {
+            Ok(index) => captures.get(index),
+            Err(_) => captures.name(prop)
+        };
+        group.map(|x| String::from(x.as_str()))
+    }
+}
+
+impl std::convert::From<&str> for RegexDocument {
+    fn from(item: &str) -> Self {
+        RegexDocument{text: String::from(item)}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RegexDocument, Document};
+
+    #[test]
+    fn test_select() {
+        let doc = RegexDocument::from("<script>var price = 12.99; var discount = 0.5;</script>");
+        assert_eq!(doc.select(r"var \w+ = [\d.]+").unwrap(), Some(String::from("var price = 12.99")));
+        assert_eq!(doc.select_prop(r"var price = ([\d.]+)", "1").unwrap(), Some(String::from("12.99")));
+        assert_eq!(doc.select_prop(r"var price = (?P<price>[\d.]+)", "price").unwrap(), Some(String::from("12.99")));
+        assert_eq!(doc.select_all_prop(r"var \w+ = ([\d.]+)", "1").unwrap(), Some(vec![String::from("12.99"), String::from("0.5")]));
+        assert_eq!(doc.select_all(r"const").unwrap(), None);
+        assert_eq!(doc.select_each_prop(r"var (p)?\w+", "1").unwrap(), vec![Some(String::from("p")), None]);
+        assert!(doc.select("(").is_err());
+    }
+
+    #[test]
+    fn test_select_all_captures() {
+        let doc = RegexDocument::from("a=1;b=2;c");
+        let captures = doc.select_all_captures(r"(?P<key>\w)=(?P<value>\d)", "0").unwrap();
+        assert_eq!(captures.len(), 2);
+        assert_eq!(captures[1].0, "b=2");
+        assert_eq!(captures[1].1.get("key").unwrap(), "b");
+        assert_eq!(captures[1].1.get("value").unwrap(), "2");
+    }
+}
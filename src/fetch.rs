@@ -0,0 +1,808 @@
+pub mod cache;
+pub mod charset;
+pub mod cookie;
+pub mod limits;
+pub mod politeness;
+pub mod resolve;
+pub mod retry;
+pub mod robots;
+
+use crate::document::{CommonError, ParseError};
+use cache::{CacheEntry, CacheOptions, DiskCache};
+use cookie::CookieJar;
+use limits::{DocFormat, FetchLimits};
+use politeness::{Politeness, PolitenessOptions};
+#[cfg(not(test))]
+use resolve::{AddressGuard, Guard, RedirectGuard};
+use robots::Robots;
+use std::collections::HashMap;
+#[cfg(not(test))]
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The timeout of a request if it is not given.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The headers carrying the credentials of a request, which is never cached.
+const CREDENTIAL_HEADERS: &[&str] = &["authorization", "cookie", "proxy-authorization"];
+/// The maximum number of the redirects followed by a request, the same as the default of reqwest.
+#[cfg(not(test))]
+const MAX_REDIRECTS: usize = 10;
+
+/// The options of the HTTP requests. The command line gives the options of all the requests, and
+/// each token can override them by its own attributes.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct RequestOptions {
+    pub method: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub form: Vec<(String, String)>,
+    pub json_body: Option<String>,
+    pub timeout: Option<Duration>,
+    /// How many times to retry a request failed by a connection error, a timeout, 429 or 5xx.
+    pub retries: Option<u32>,
+    /// The delay before the first retry, which is doubled for each of the next retries.
+    pub retry_delay: Option<Duration>,
+    /// The encoding of the document, which overrides the detected one.
+    pub encoding: Option<String>,
+    /// The format that the document is read as, which decides the content types accepted.
+    pub format: Option<DocFormat>
+}
+
+impl RequestOptions {
+    /// Returns the options overridden by `other`. The headers are overridden by name, and the other
+    /// options are overridden if they are set in `other`.
+    pub fn merge(&self, other: &RequestOptions) -> RequestOptions {
+        let mut headers: Vec<(String, String)> = self.headers.iter()
+            .filter(|(name, _)| !other.headers.iter().any(|(other_name, _)| other_name.eq_ignore_ascii_case(name)))
+            .cloned()
+            .collect();
+        headers.extend(other.headers.iter().cloned());
+        RequestOptions{
+            method: other.method.clone().or_else(|| self.method.clone()),
+            headers,
+            form: if other.form.is_empty() { self.form.clone() } else { other.form.clone() },
+            json_body: other.json_body.clone().or_else(|| self.json_body.clone()),
+            timeout: other.timeout.or(self.timeout),
+            retries: other.retries.or(self.retries),
+            retry_delay: other.retry_delay.or(self.retry_delay),
+            encoding: other.encoding.clone().or_else(|| self.encoding.clone()),
+            format: other.format.or(self.format)
+        }
+    }
+
+    /// The method of the request, `POST` by default if there is a body, otherwise `GET`.
+    pub fn method(&self) -> String {
+        match &self.method {
+            Some(method) => method.to_uppercase(),
+            None if !self.form.is_empty() || self.json_body.is_some() => String::from("POST"),
+            None => String::from("GET")
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header_name, _)| header_name.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header_name, _)| header_name.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// The error of sending a request, before any response.
+#[derive(Debug,Clone)]
+pub struct SendError {
+    message: String,
+    /// A connection error or a timeout is worth retrying, an invalid request is not.
+    retryable: bool
+}
+
+/// The final error of fetching a document, after all the retries.
+#[derive(Debug,Clone)]
+pub struct FetchError {
+    pub url: String,
+    /// The status of the last response, if there was a response.
+    pub status: Option<u16>,
+    pub attempts: u32,
+    pub message: String
+}
+
+impl std::error::Error for FetchError {}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[FETCH ERROR] failed to fetch the document at: {}", self.url)?;
+        if let Some(status) = self.status {
+            write!(f, ", the last status is {}", status)?;
+        }
+        write!(f, " after {} attempt(s): {}", self.attempts, self.message)
+    }
+}
+
+/// The fetch layer under the tokens. It sends the requests for the documents with the options of
+/// the command line, keeps the cookies between the requests, and requests each host politely.
+#[derive(Debug)]
+pub struct Fetcher {
+    options: RequestOptions,
+    cookie_jar: Option<CookieJar>,
+    cache: Option<DiskCache>,
+    limits: FetchLimits,
+    politeness: Politeness,
+    /// The robots.txt rules of each origin and user agent, e.g. `https://www.statcan.gc.ca` and `dessert3`.
+    robots: Mutex<HashMap<(String, String), Robots>>,
+    #[cfg(not(test))]
+    client: reqwest::blocking::Client,
+    #[cfg(not(test))]
+    redirect_guard: Option<RedirectGuard>,
+    /// The addresses of the hosts are checked before the requests if there is the guard, and each
+    /// host is connected by a client pinned to its checked addresses.
+    #[cfg(not(test))]
+    address_guard: Option<AddressGuard>,
+    #[cfg(not(test))]
+    pinned_clients: Mutex<HashMap<String, reqwest::blocking::Client>>,
+    #[cfg(test)]
+    responses: Mutex<HashMap<String, Vec<Result<Response, SendError>>>>,
+    #[cfg(test)]
+    requests: Mutex<Vec<(String, RequestOptions)>>,
+    #[cfg(test)]
+    sleeps: Mutex<Vec<Duration>>
+}
+
+impl Fetcher {
+    pub fn new(options: RequestOptions) -> Self {
+        Fetcher{
+            options,
+            cookie_jar: None,
+            cache: None,
+            limits: FetchLimits::default(),
+            politeness: Politeness::new(PolitenessOptions::default()),
+            robots: Mutex::new(HashMap::new()),
+            #[cfg(not(test))]
+            client: reqwest::blocking::Client::new(),
+            #[cfg(not(test))]
+            redirect_guard: None,
+            #[cfg(not(test))]
+            address_guard: None,
+            #[cfg(not(test))]
+            pinned_clients: Mutex::new(HashMap::new()),
+            #[cfg(test)]
+            responses: Mutex::new(HashMap::new()),
+            #[cfg(test)]
+            requests: Mutex::new(Vec::new()),
+            #[cfg(test)]
+            sleeps: Mutex::new(Vec::new())
+        }
+    }
+
+    pub fn with_cookie_jar(self, cookie_jar: CookieJar) -> Self {
+        Fetcher{cookie_jar: Some(cookie_jar), ..self}
+    }
+
+    pub fn with_cache(self, options: CacheOptions) -> Self {
+        Fetcher{cache: Some(DiskCache::new(options)), ..self}
+    }
+
+    /// Checks each redirect with the guard, which refuses the redirect by an error message.
+    #[cfg(not(test))]
+    pub fn with_redirect_guard(self, guard: impl Fn(&url::Url) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        let guard: RedirectGuard = Guard(std::sync::Arc::new(guard));
+        let policy_guard = guard.clone();
+        let policy = reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error(format!("more than {} redirects", MAX_REDIRECTS));
+            }
+            match (policy_guard.0)(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(message) => attempt.error(message)
+            }
+        });
+        let client = reqwest::blocking::Client::builder().redirect(policy).build().expect("failed to build the http client");
+        Fetcher{client, redirect_guard: Some(guard), ..self}
+    }
+
+    /// There is no redirect in the tests.
+    #[cfg(test)]
+    pub fn with_redirect_guard(self, _guard: impl Fn(&url::Url) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        self
+    }
+
+    /// Checks each address that a host resolves to with the guard before connecting to it, e.g. to
+    /// refuse the private addresses. The request connects only to the checked addresses, and the
+    /// redirects are followed by the fetcher, so that each of them is checked too.
+    #[cfg(not(test))]
+    pub fn with_address_guard(self, guard: impl Fn(&url::Url, std::net::IpAddr) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        Fetcher{address_guard: Some(Guard(std::sync::Arc::new(guard))), ..self}
+    }
+
+    /// There is no connection in the tests.
+    #[cfg(test)]
+    pub fn with_address_guard(self, _guard: impl Fn(&url::Url, std::net::IpAddr) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        self
+    }
+
+    pub fn with_limits(self, limits: FetchLimits) -> Self {
+        Fetcher{limits, ..self}
+    }
+
+    pub fn with_politeness(self, options: PolitenessOptions) -> Self {
+        Fetcher{politeness: Politeness::new(options), ..self}
+    }
+
+    /// Fetches the document by the deadline if there is one, e.g. the end of the time budget of the
+    /// render. The timeout of each attempt is cut to the time left, and a retry is not made if its
+    /// delay would end after the deadline.
+    pub fn fetch(&self, url: &str, options: &RequestOptions, deadline: Option<Instant>) -> Result<String, CommonError> {
+        let own_retries = options.retries;
+        let mut options = self.options.merge(options);
+        options.timeout = Some(options.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        let time_left = || deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if let Some(time_left) = time_left() {
+            options.timeout = options.timeout.map(|timeout| timeout.min(time_left));
+        }
+        let parse_url = || url::Url::parse(url).map_err(|e| Box::new(ParseError::new_str(format!("invalid url '{}': {}", url, e))));
+        let cookie_jar = match &self.cookie_jar {
+            Some(cookie_jar) => Some((cookie_jar, parse_url()?)),
+            None => None
+        };
+        if let Some((cookie_jar, parsed_url)) = &cookie_jar {
+            if options.header("cookie").is_none() {
+                if let Some(cookie) = cookie_jar.header(parsed_url) {
+                    options.headers.push((String::from("cookie"), cookie));
+                }
+            }
+        }
+
+        // Only the `GET` requests are cached, by the url and the headers. The requests with credentials
+        // are not cached, so that their tokens are never written in the cache files.
+        let has_credentials = CREDENTIAL_HEADERS.iter().any(|name| options.header(name).is_some());
+        let cache = self.cache.as_ref().filter(|_| options.method() == "GET" && !has_credentials).map(|cache| {
+            let mut key = format!("GET {}", url);
+            options.headers.iter().for_each(|(name, value)| key.push_str(&format!("\n{}: {}", name.to_lowercase(), value)));
+            (cache, key)
+        });
+        let cached = match &cache {
+            Some((cache, key)) if !cache.options().refresh => cache.load(key),
+            _ => None
+        };
+        if let (Some((cache, _)), Some(entry)) = (&cache, &cached) {
+            if entry.is_fresh(cache.options().ttl) {
+                log::trace!("{} {}: taken from the cache", options.method(), url);
+                return self.read_body(url, Some(200), 0, &options, entry.content_type.as_deref(), &entry.body);
+            }
+            if let Some(etag) = &entry.etag {
+                options.headers.push((String::from("if-none-match"), etag.clone()));
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                options.headers.push((String::from("if-modified-since"), last_modified.clone()));
+            }
+        }
+
+        if self.politeness.options().robots {
+            if let Some(message) = self.check_robots(&parse_url()?, &options)? {
+                return Err(Box::new(FetchError{url: String::from(url), status: None, attempts: 0, message}));
+            }
+        }
+
+        let retries = match own_retries {
+            Some(retries) => retries,
+            None if retry::is_retried_by_default(&options.method()) => options.retries.unwrap_or(retry::DEFAULT_RETRIES),
+            None => 0
+        };
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            if let Some(time_left) = time_left() {
+                options.timeout = options.timeout.map(|timeout| timeout.min(time_left));
+            }
+            let started = Instant::now();
+            let (status, message, retryable, retry_after) = match self.send_politely(url, &options) {
+                Ok(response) => {
+                    log::trace!("{} {}: status {}, {} bytes in {:?}", options.method(), url, response.status, response.body.len(), started.elapsed());
+                    if let Some((cookie_jar, parsed_url)) = &cookie_jar {
+                        response.headers.iter()
+                            .filter(|(name, _)| name.eq_ignore_ascii_case("set-cookie"))
+                            .for_each(|(_, value)| cookie_jar.store(parsed_url, value));
+                    }
+                    if let (Some((cache, key)), Some(entry)) = (&cache, &cached) {
+                        if response.status == 304 {
+                            store_in_cache(cache, key, &CacheEntry{stored_at: SystemTime::now(), ..entry.clone()});
+                            return self.read_body(url, Some(200), attempts, &options, entry.content_type.as_deref(), &entry.body);
+                        }
+                    }
+                    if response.status < 400 {
+                        let text = self.read_body(url, Some(response.status), attempts, &options, response.header("content-type"), &response.body)?;
+                        if let Some((cache, key)) = &cache {
+                            if (200..300).contains(&response.status) {
+                                let header = |name| response.header(name).map(String::from);
+                                store_in_cache(cache, key, &CacheEntry{
+                                    stored_at: SystemTime::now(),
+                                    etag: header("etag"),
+                                    last_modified: header("last-modified"),
+                                    content_type: header("content-type"),
+                                    body: response.body.clone()
+                                });
+                            }
+                        }
+                        return Ok(text);
+                    }
+                    let retry_after = response.header("retry-after").and_then(retry::parse_retry_after);
+                    (Some(response.status), format!("the server responded with the status {}", response.status), retry::is_retryable_status(response.status), retry_after)
+                },
+                Err(error) => {
+                    log::trace!("{} {}: failed in {:?}: {}", options.method(), url, started.elapsed(), error.message);
+                    (None, error.message, error.retryable, None)
+                }
+            };
+            if !retryable || attempts > retries {
+                return Err(Box::new(FetchError{url: String::from(url), status, attempts, message}));
+            }
+            let delay = retry_after.unwrap_or_else(|| retry::backoff(options.retry_delay.unwrap_or(retry::DEFAULT_RETRY_DELAY), attempts));
+            if time_left().map(|time_left| delay >= time_left).unwrap_or(false) {
+                return Err(Box::new(FetchError{url: String::from(url), status, attempts, message: format!("{}, and no time is left to retry", message)}));
+            }
+            self.sleep(delay);
+        }
+    }
+
+    /// Checks the body of a response, received or cached, with the limits, and decodes it.
+    fn read_body(&self, url: &str, status: Option<u16>, attempts: u32, options: &RequestOptions, content_type: Option<&str>, body: &[u8]) -> Result<String, CommonError> {
+        if let Err(message) = self.limits.check(options.format, content_type, body) {
+            return Err(Box::new(FetchError{url: String::from(url), status, attempts, message}));
+        }
+        charset::decode(body, content_type, options.encoding.as_deref())
+    }
+
+    /// Sends the request once the host is ready for it.
+    fn send_politely(&self, url: &str, options: &RequestOptions) -> Result<Response, SendError> {
+        let host = url::Url::parse(url).ok().and_then(|x| x.host_str().map(String::from)).unwrap_or_else(|| String::from(url));
+        let (_permit, delay) = self.politeness.acquire(&host);
+        if delay > Duration::ZERO {
+            self.sleep(delay);
+        }
+        self.send(url, options)
+    }
+
+    /// Checks the url with the robots.txt of its origin, which is requested once for each origin and
+    /// user agent, and returns why the url is disallowed. The url is allowed if the origin has no
+    /// robots.txt, e.g. `404`. It is disallowed for now if the robots.txt is unreachable, e.g. `503`,
+    /// which is requested again by the next url of the origin.
+    fn check_robots(&self, url: &url::Url, options: &RequestOptions) -> Result<Option<String>, CommonError> {
+        let key = (url.origin().ascii_serialization(), String::from(options.header("user-agent").unwrap_or("dessert3")));
+        if !self.robots.lock().unwrap().contains_key(&key) {
+            let text = match &self.politeness.options().robots_file {
+                Some(robots_file) => std::fs::read_to_string(robots_file)
+                    .map_err(|e| Box::new(ParseError::new_str(format!("failed to read the robots file {}: {}", robots_file.display(), e))))?,
+                None => {
+                    let robots_options = RequestOptions{headers: options.headers.clone(), timeout: options.timeout, ..Default::default()};
+                    match self.send_politely(&format!("{}/robots.txt", key.0), &robots_options) {
+                        Ok(response) if response.status == 200 => String::from_utf8_lossy(&response.body).into_owned(),
+                        Ok(response) if response.status >= 500 => return Ok(Some(format!("the robots.txt is unreachable for now, the status is {}", response.status))),
+                        Ok(_) => String::new(),
+                        Err(error) => return Ok(Some(format!("the robots.txt is unreachable for now: {}", error.message)))
+                    }
+                }
+            };
+            let robots = Robots::parse(&text, &key.1);
+            self.robots.lock().unwrap().insert(key.clone(), robots);
+        }
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => String::from(url.path())
+        };
+        match self.robots.lock().unwrap()[&key].is_allowed(&path) {
+            true => Ok(None),
+            false => Ok(Some(String::from("the url is disallowed by the robots.txt")))
+        }
+    }
+
+    /// Writes the cookies back to the cookie jar file, if there is one.
+    pub fn save_cookies(&self) -> Result<(), CommonError> {
+        match &self.cookie_jar {
+            Some(cookie_jar) => cookie_jar.save(),
+            None => Ok(())
+        }
+    }
+
+    #[cfg(not(test))]
+    fn send(&self, url: &str, options: &RequestOptions) -> Result<Response, SendError> {
+        match &self.address_guard {
+            Some(address_guard) => self.send_pinned(url, options, address_guard),
+            None => self.send_by(&self.client, url, options)
+        }
+    }
+
+    /// Sends the request by the clients pinned to the checked addresses of the hosts, and follows
+    /// the redirects the same way as reqwest: `301`, `302` and `303` turn the request into a `GET`
+    /// without body, and the credentials are not sent to another host.
+    #[cfg(not(test))]
+    fn send_pinned(&self, url: &str, options: &RequestOptions, address_guard: &AddressGuard) -> Result<Response, SendError> {
+        let refused = |message: String| SendError{message, retryable: false};
+        let mut url = url::Url::parse(url).map_err(|e| refused(format!("invalid url '{}': {}", url, e)))?;
+        let mut options = options.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let client = self.pinned_client(&url, address_guard)?;
+            let response = self.send_by(&client, url.as_str(), &options)?;
+            let location = match response.status {
+                301 | 302 | 303 | 307 | 308 => response.header("location"),
+                _ => None
+            };
+            let next = match location {
+                Some(location) => url.join(location).map_err(|e| refused(format!("invalid redirect '{}': {}", location, e)))?,
+                None => return Ok(response)
+            };
+            if let Some(redirect_guard) = &self.redirect_guard {
+                (redirect_guard.0)(&next).map_err(refused)?;
+            }
+            if response.status <= 303 && options.method() != "HEAD" {
+                options.method = Some(String::from("GET"));
+                options.form.clear();
+                options.json_body = None;
+            }
+            if next.host_str() != url.host_str() {
+                options.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("authorization") && !name.eq_ignore_ascii_case("cookie"));
+            }
+            url = next;
+        }
+        Err(refused(format!("more than {} redirects", MAX_REDIRECTS)))
+    }
+
+    /// The client of the host, which connects only to the addresses of the host checked by the guard.
+    #[cfg(not(test))]
+    fn pinned_client(&self, url: &url::Url, address_guard: &AddressGuard) -> Result<reqwest::blocking::Client, SendError> {
+        let host = url.host_str().unwrap_or("");
+        if let Some(client) = self.pinned_clients.lock().unwrap().get(host) {
+            return Ok(client.clone());
+        }
+        let addresses = resolve::resolve_checked(url, address_guard).map_err(|message| SendError{message, retryable: false})?;
+        let client = reqwest::blocking::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve_to_addrs(host, &addresses)
+            .build()
+            .map_err(|e| SendError{message: format!("failed to build the http client: {}", e), retryable: false})?;
+        self.pinned_clients.lock().unwrap().insert(String::from(host), client.clone());
+        Ok(client)
+    }
+
+    #[cfg(not(test))]
+    fn send_by(&self, client: &reqwest::blocking::Client, url: &str, options: &RequestOptions) -> Result<Response, SendError> {
+        let method = reqwest::Method::from_bytes(options.method().as_bytes())
+            .map_err(|_| SendError{message: format!("invalid request method: {}", options.method()), retryable: false})?;
+        let mut request = client.request(method, url).timeout(options.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        for (name, value) in &options.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        if !options.form.is_empty() {
+            request = request.form(&options.form);
+        } else if let Some(json_body) = &options.json_body {
+            if options.header("content-type").is_none() {
+                request = request.header("content-type", "application/json");
+            }
+            request = request.body(json_body.clone());
+        }
+        let to_send_error = |e: reqwest::Error| SendError{retryable: e.is_connect() || e.is_timeout() || e.is_body(), message: e.to_string()};
+        let response = request.send().map_err(to_send_error)?;
+        let status = response.status().as_u16();
+        let headers = response.headers().iter()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect::<Vec<(String, String)>>();
+        // A document of a refused type is not downloaded at all, e.g. a large PDF.
+        if (200..300).contains(&status) {
+            let content_type = headers.iter().find(|(name, _)| name == "content-type").map(|(_, value)| value.as_str());
+            self.limits.check_content_type(options.format, content_type).map_err(|message| SendError{message, retryable: false})?;
+        }
+        // At most one byte more than the limit is read, which is enough to tell that the body is too large.
+        let mut body = Vec::new();
+        response.take(self.limits.max_body_size as u64 + 1).read_to_end(&mut body)
+            .map_err(|e| SendError{message: format!("failed to read the response: {}", e), retryable: true})?;
+        Ok(Response{status, headers, body})
+    }
+
+    #[cfg(not(test))]
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+
+    #[cfg(test)]
+    fn send(&self, url: &str, options: &RequestOptions) -> Result<Response, SendError> {
+        self.requests.lock().unwrap().push((String::from(url), options.clone()));
+        let mut responses = self.responses.lock().unwrap();
+        match responses.get_mut(url) {
+            // The last response is repeated for the next requests.
+            Some(queue) if queue.len() > 1 => queue.remove(0),
+            Some(queue) => queue[0].clone(),
+            None => Ok(Response{status: 200, headers: Vec::new(), body: Vec::new()})
+        }
+    }
+
+    #[cfg(test)]
+    fn sleep(&self, duration: Duration) {
+        self.sleeps.lock().unwrap().push(duration);
+    }
+
+    #[cfg(test)]
+    pub fn with_response(self, url: &str, response: Response) -> Self {
+        self.responses.lock().unwrap().entry(String::from(url)).or_default().push(Ok(response));
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_send_error(self, url: &str, message: &str, retryable: bool) -> Self {
+        self.responses.lock().unwrap().entry(String::from(url)).or_default().push(Err(SendError{message: String::from(message), retryable}));
+        self
+    }
+
+    #[cfg(test)]
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.sleeps.lock().unwrap().clone()
+    }
+
+    #[cfg(test)]
+    pub fn requests(&self) -> Vec<(String, RequestOptions)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Stores the response in the cache. A failure to write the cache does not fail the fetch, which
+/// has its document anyway.
+fn store_in_cache(cache: &DiskCache, key: &str, entry: &CacheEntry) {
+    if let Err(error) = cache.store(key, entry) {
+        log::trace!("{}: not cached: {}", key.lines().next().unwrap_or(key), error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fetcher, FetchError, RequestOptions, Response};
+    use super::cache::{CacheOptions, DEFAULT_CACHE_TTL};
+    use super::limits::{DocFormat, FetchLimits};
+    use super::politeness::PolitenessOptions;
+    use std::time::{Duration, Instant};
+    use super::cookie::CookieJar;
+
+    fn headers(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let global = RequestOptions{headers: headers(&[("User-Agent", "Mozilla/5.0"), ("Accept", "*/*")]), ..Default::default()};
+        let token = RequestOptions{method: Some(String::from("post")), headers: headers(&[("accept", "text/html")]), ..Default::default()};
+        let merged = global.merge(&token);
+        assert_eq!(merged.headers, headers(&[("User-Agent", "Mozilla/5.0"), ("accept", "text/html")]));
+        assert_eq!(merged.method(), "POST");
+        assert_eq!(global.method(), "GET");
+        let form = RequestOptions{form: headers(&[("q", "canada")]), ..Default::default()};
+        assert_eq!(global.merge(&form).method(), "POST");
+    }
+
+    #[test]
+    fn test_fetch_with_cookies() {
+        let cookie_jar = CookieJar::from_text("example.com\tTRUE\t/\tFALSE\t0\tsession\tabc\n", None);
+        let response = Response{status: 200, headers: headers(&[("Set-Cookie", "lang=fr; Path=/")]), body: Vec::from("<p>hi</p>")};
+        let fetcher = Fetcher::new(RequestOptions{headers: headers(&[("user-agent", "Mozilla/5.0")]), ..Default::default()}).with_cookie_jar(cookie_jar)
+            .with_response("https://www.example.com/a", response);
+        assert_eq!(fetcher.fetch("https://www.example.com/a", &RequestOptions::default(), None).unwrap(), "<p>hi</p>");
+        assert_eq!(fetcher.fetch("https://www.example.com/b", &RequestOptions::default(), None).unwrap(), "");
+        let requests = fetcher.requests();
+        assert_eq!(requests[0].1.headers, headers(&[("user-agent", "Mozilla/5.0"), ("cookie", "session=abc")]));
+        assert_eq!(requests[1].1.headers, headers(&[("user-agent", "Mozilla/5.0"), ("cookie", "session=abc; lang=fr")]));
+        assert!(fetcher.fetch("not a url", &RequestOptions::default(), None).is_err());
+    }
+
+    #[test]
+    fn test_fetch_with_retries() {
+        let unavailable = Response{status: 503, headers: headers(&[("Retry-After", "7")]), body: Vec::new()};
+        let too_many = Response{status: 429, headers: Vec::new(), body: Vec::new()};
+        let ok = Response{status: 200, headers: Vec::new(), body: Vec::from("ok")};
+        let fetcher = Fetcher::new(RequestOptions{retries: Some(3), retry_delay: Some(Duration::from_millis(100)), ..Default::default()})
+            .with_send_error("https://mock/a", "connection refused", true)
+            .with_response("https://mock/a", unavailable)
+            .with_response("https://mock/a", too_many)
+            .with_response("https://mock/a", ok);
+        assert_eq!(fetcher.fetch("https://mock/a", &RequestOptions::default(), None).unwrap(), "ok");
+        assert_eq!(fetcher.requests().len(), 4);
+        let sleeps = fetcher.sleeps();
+        assert!(sleeps[0] >= Duration::from_millis(50) && sleeps[0] <= Duration::from_millis(100));
+        assert_eq!(sleeps[1], Duration::from_secs(7));
+        assert!(sleeps[2] >= Duration::from_millis(200) && sleeps[2] <= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_fetch_post_with_retries() {
+        let unavailable = Response{status: 503, headers: Vec::new(), body: Vec::new()};
+        let fetcher = Fetcher::new(RequestOptions{retries: Some(3), ..Default::default()}).with_response("https://mock/a", unavailable);
+        let form = RequestOptions{form: vec![(String::from("q"), String::from("x"))], ..Default::default()};
+        let error = fetcher.fetch("https://mock/a", &form, None).unwrap_err();
+        assert_eq!(error.downcast_ref::<FetchError>().unwrap().attempts, 1);
+        let json = RequestOptions{json_body: Some(String::from("{}")), ..Default::default()};
+        assert_eq!(fetcher.fetch("https://mock/a", &json, None).unwrap_err().downcast_ref::<FetchError>().unwrap().attempts, 1);
+        // The token can retry its own request.
+        let error = fetcher.fetch("https://mock/a", &RequestOptions{retries: Some(1), ..form}, None).unwrap_err();
+        assert_eq!(error.downcast_ref::<FetchError>().unwrap().attempts, 2);
+        let head = RequestOptions{method: Some(String::from("head")), ..Default::default()};
+        assert_eq!(fetcher.fetch("https://mock/a", &head, None).unwrap_err().downcast_ref::<FetchError>().unwrap().attempts, 4);
+    }
+
+    #[test]
+    fn test_fetch_by_deadline() {
+        let unavailable = Response{status: 503, headers: headers(&[("Retry-After", "60")]), body: Vec::new()};
+        let fetcher = Fetcher::new(RequestOptions{retries: Some(3), ..Default::default()}).with_response("https://mock/a", unavailable);
+        let error = fetcher.fetch("https://mock/a", &RequestOptions::default(), Some(Instant::now() + Duration::from_secs(1))).unwrap_err();
+        assert_eq!(error.to_string(), "[FETCH ERROR] failed to fetch the document at: https://mock/a, the last status is 503 after 1 attempt(s): the server responded with the status 503, and no time is left to retry");
+        assert!(fetcher.sleeps().is_empty());
+        let requests = fetcher.requests();
+        assert!(requests[0].1.timeout.unwrap() <= Duration::from_secs(1));
+        assert!(fetcher.fetch("https://mock/a", &RequestOptions::default(), None).is_err());
+        assert_eq!(fetcher.sleeps(), vec![Duration::from_secs(60); 3]);
+        assert_eq!(fetcher.requests()[1].1.timeout, Some(super::DEFAULT_TIMEOUT));
+    }
+
+    #[test]
+    fn test_fetch_error() {
+        let unavailable = Response{status: 503, headers: Vec::new(), body: Vec::new()};
+        let fetcher = Fetcher::new(RequestOptions::default())
+            .with_response("https://mock/a", unavailable)
+            .with_response("https://mock/b", Response{status: 404, headers: Vec::new(), body: Vec::new()})
+            .with_send_error("https://mock/c", "invalid request method", false);
+        let error = fetcher.fetch("https://mock/a", &RequestOptions{retries: Some(1), ..Default::default()}, None).unwrap_err();
+        let error = error.downcast_ref::<FetchError>().unwrap();
+        assert_eq!((error.status, error.attempts), (Some(503), 2));
+        assert_eq!(error.to_string(), "[FETCH ERROR] failed to fetch the document at: https://mock/a, the last status is 503 after 2 attempt(s): the server responded with the status 503");
+        let error = fetcher.fetch("https://mock/b", &RequestOptions::default(), None).unwrap_err();
+        assert_eq!(error.downcast_ref::<FetchError>().unwrap().attempts, 1);
+        let error = fetcher.fetch("https://mock/c", &RequestOptions::default(), None).unwrap_err();
+        assert_eq!(error.to_string(), "[FETCH ERROR] failed to fetch the document at: https://mock/c after 1 attempt(s): invalid request method");
+        assert_eq!(fetcher.requests().len(), 4);
+    }
+
+    #[test]
+    fn test_fetch_politely() {
+        let fetcher = Fetcher::new(RequestOptions::default()).with_politeness(PolitenessOptions{min_delay: Some(Duration::from_secs(5)), ..Default::default()});
+        fetcher.fetch("https://mock/a", &RequestOptions::default(), None).unwrap();
+        fetcher.fetch("https://mock/b", &RequestOptions::default(), None).unwrap();
+        fetcher.fetch("https://other/a", &RequestOptions::default(), None).unwrap();
+        let sleeps = fetcher.sleeps();
+        assert_eq!(sleeps.len(), 1);
+        assert!(sleeps[0] > Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_fetch_with_robots() {
+        let robots = Response{status: 200, headers: Vec::new(), body: Vec::from("User-agent: *\nDisallow: /private\n")};
+        let fetcher = Fetcher::new(RequestOptions::default())
+            .with_politeness(PolitenessOptions{robots: true, ..Default::default()})
+            .with_response("https://mock/robots.txt", robots);
+        fetcher.fetch("https://mock/public", &RequestOptions::default(), None).unwrap();
+        let error = fetcher.fetch("https://mock/private/a", &RequestOptions::default(), None).unwrap_err();
+        assert_eq!(error.to_string(), "[FETCH ERROR] failed to fetch the document at: https://mock/private/a after 0 attempt(s): the url is disallowed by the robots.txt");
+        fetcher.fetch("https://other/private/a", &RequestOptions::default(), None).unwrap();
+        let urls: Vec<String> = fetcher.requests().into_iter().map(|(url, _)| url).collect();
+        assert_eq!(urls, vec!["https://mock/robots.txt", "https://mock/public", "https://other/robots.txt", "https://other/private/a"]);
+
+        // An unreachable robots.txt disallows the urls until it is reachable.
+        let fetcher = Fetcher::new(RequestOptions::default())
+            .with_politeness(PolitenessOptions{robots: true, ..Default::default()})
+            .with_response("https://mock/robots.txt", Response{status: 503, headers: Vec::new(), body: Vec::new()})
+            .with_response("https://mock/robots.txt", Response{status: 404, headers: Vec::new(), body: Vec::new()});
+        let error = fetcher.fetch("https://mock/a", &RequestOptions::default(), None).unwrap_err();
+        assert_eq!(error.to_string(), "[FETCH ERROR] failed to fetch the document at: https://mock/a after 0 attempt(s): the robots.txt is unreachable for now, the status is 503");
+        fetcher.fetch("https://mock/a", &RequestOptions::default(), None).unwrap();
+        fetcher.fetch("https://mock/b", &RequestOptions::default(), None).unwrap();
+        let urls: Vec<String> = fetcher.requests().into_iter().map(|(url, _)| url).collect();
+        assert_eq!(urls, vec!["https://mock/robots.txt", "https://mock/robots.txt", "https://mock/a", "https://mock/b"]);
+    }
+
+    #[test]
+    fn test_fetch_with_robots_file() {
+        let robots_file = std::env::temp_dir().join(format!("dessert3-robots-{}.txt", std::process::id()));
+        std::fs::write(&robots_file, "User-agent: dessert3\nDisallow: /\n").unwrap();
+        let fetcher = Fetcher::new(RequestOptions::default())
+            .with_politeness(PolitenessOptions{robots: true, robots_file: Some(robots_file.clone()), ..Default::default()});
+        assert!(fetcher.fetch("https://mock/a", &RequestOptions::default(), None).is_err());
+        assert!(fetcher.requests().is_empty());
+        // The rules of another user agent are not the ones taken for `dessert3`.
+        assert!(fetcher.fetch("https://mock/a", &RequestOptions{headers: headers(&[("user-agent", "Mozilla/5.0")]), ..Default::default()}, None).is_ok());
+        assert!(fetcher.fetch("https://mock/a", &RequestOptions::default(), None).is_err());
+        assert_eq!(fetcher.requests().len(), 1);
+        std::fs::remove_file(robots_file).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_with_cache() {
+        let dir = std::env::temp_dir().join(format!("dessert3-fetch-cache-{}", std::process::id()));
+        let cache_options = CacheOptions{dir: dir.clone(), ttl: DEFAULT_CACHE_TTL, refresh: false};
+        let first = Response{status: 200, headers: headers(&[("ETag", "\"v1\""), ("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT")]), body: Vec::from("v1")};
+        let fetcher = Fetcher::new(RequestOptions::default()).with_cache(cache_options.clone()).with_response("https://mock", first);
+        assert_eq!(fetcher.fetch("https://mock", &RequestOptions::default(), None).unwrap(), "v1");
+        assert_eq!(fetcher.fetch("https://mock", &RequestOptions::default(), None).unwrap(), "v1");
+        fetcher.fetch("https://mock", &RequestOptions{method: Some(String::from("POST")), ..Default::default()}, None).unwrap();
+        fetcher.fetch("https://mock", &RequestOptions{headers: headers(&[("accept-language", "fr")]), ..Default::default()}, None).unwrap();
+        assert_eq!(fetcher.requests().len(), 3);
+
+        // The stale document is revalidated.
+        let not_modified = Response{status: 304, headers: Vec::new(), body: Vec::new()};
+        let fetcher = Fetcher::new(RequestOptions::default()).with_cache(CacheOptions{ttl: Duration::ZERO, ..cache_options.clone()}).with_response("https://mock", not_modified);
+        assert_eq!(fetcher.fetch("https://mock", &RequestOptions::default(), None).unwrap(), "v1");
+        let request_headers = &fetcher.requests()[0].1.headers;
+        assert_eq!(request_headers, &headers(&[("if-none-match", "\"v1\""), ("if-modified-since", "Wed, 21 Oct 2015 07:28:00 GMT")]));
+
+        // The refresh fetches the document again without the validators.
+        let second = Response{status: 200, headers: Vec::new(), body: Vec::from("v2")};
+        let fetcher = Fetcher::new(RequestOptions::default()).with_cache(CacheOptions{refresh: true, ..cache_options.clone()}).with_response("https://mock", second);
+        assert_eq!(fetcher.fetch("https://mock", &RequestOptions::default(), None).unwrap(), "v2");
+        assert!(fetcher.requests()[0].1.headers.is_empty());
+        let fetcher = Fetcher::new(RequestOptions::default()).with_cache(cache_options);
+        assert_eq!(fetcher.fetch("https://mock", &RequestOptions::default(), None).unwrap(), "v2");
+        assert!(fetcher.requests().is_empty());
+
+        // The requests with credentials are neither cached nor taken from the cache.
+        let authorized = RequestOptions{headers: headers(&[("Authorization", "Bearer secret")]), ..Default::default()};
+        fetcher.fetch("https://mock", &authorized, None).unwrap();
+        fetcher.fetch("https://mock", &authorized, None).unwrap();
+        assert_eq!(fetcher.requests().len(), 2);
+        for file in std::fs::read_dir(&dir).unwrap() {
+            assert!(!std::fs::read_to_string(file.unwrap().path()).unwrap().contains("secret"));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // A cache which can not be written does not fail the fetch.
+        std::fs::write(&dir, "not a directory").unwrap();
+        let fetcher = Fetcher::new(RequestOptions::default()).with_cache(CacheOptions{dir: dir.clone(), ttl: DEFAULT_CACHE_TTL, refresh: false})
+            .with_response("https://mock", Response{status: 200, headers: Vec::new(), body: Vec::from("v3")});
+        assert_eq!(fetcher.fetch("https://mock", &RequestOptions::default(), None).unwrap(), "v3");
+        std::fs::remove_file(dir).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_with_cache_and_encoding() {
+        let dir = std::env::temp_dir().join(format!("dessert3-fetch-cache-encoding-{}", std::process::id()));
+        let cache_options = CacheOptions{dir: dir.clone(), ttl: DEFAULT_CACHE_TTL, refresh: false};
+        let response = Response{status: 200, headers: headers(&[("Content-Type", "text/html; charset=Shift_JIS")]), body: b"<p>\x93\xfa\x96\x7b</p>".to_vec()};
+        let fetcher = Fetcher::new(RequestOptions::default()).with_cache(cache_options.clone()).with_response("https://mock", response);
+        let latin1 = RequestOptions{encoding: Some(String::from("latin1")), ..Default::default()};
+        assert_eq!(fetcher.fetch("https://mock", &latin1, None).unwrap(), "<p>\u{201c}\u{fa}\u{2013}{</p>");
+
+        // The cached body is decoded again with the encoding of each request.
+        let fetcher = Fetcher::new(RequestOptions::default()).with_cache(cache_options.clone());
+        assert_eq!(fetcher.fetch("https://mock", &RequestOptions::default(), None).unwrap(), "<p>日本</p>");
+        assert_eq!(fetcher.fetch("https://mock", &latin1, None).unwrap(), "<p>\u{201c}\u{fa}\u{2013}{</p>");
+        let fetcher = Fetcher::new(RequestOptions{encoding: Some(String::from("Shift_JIS")), ..Default::default()}).with_cache(cache_options);
+        assert_eq!(fetcher.fetch("https://mock", &latin1, None).unwrap(), "<p>\u{201c}\u{fa}\u{2013}{</p>");
+        assert_eq!(fetcher.fetch("https://mock", &RequestOptions::default(), None).unwrap(), "<p>日本</p>");
+        assert!(fetcher.requests().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_with_encoding() {
+        let response = Response{status: 200, headers: headers(&[("Content-Type", "text/html; charset=Shift_JIS")]), body: b"<p>\x93\xfa\x96\x7b</p>".to_vec()};
+        let fetcher = Fetcher::new(RequestOptions::default()).with_response("https://mock", response);
+        assert_eq!(fetcher.fetch("https://mock", &RequestOptions::default(), None).unwrap(), "<p>日本</p>");
+        let latin1 = RequestOptions{encoding: Some(String::from("latin1")), ..Default::default()};
+        assert_eq!(fetcher.fetch("https://mock", &latin1, None).unwrap(), "<p>\u{201c}\u{fa}\u{2013}{</p>");
+    }
+
+    #[test]
+    fn test_fetch_with_limits() {
+        let pdf = Response{status: 200, headers: headers(&[("Content-Type", "application/pdf")]), body: Vec::from("%PDF-1.4")};
+        let fetcher = Fetcher::new(RequestOptions::default())
+            .with_limits(FetchLimits{max_body_size: 8, ..Default::default()})
+            .with_response("https://mock/a.pdf", pdf)
+            .with_response("https://mock/large", Response{status: 200, headers: Vec::new(), body: Vec::from("<p>large</p>")});
+        let html = RequestOptions{format: Some(DocFormat::Html), ..Default::default()};
+        let error = fetcher.fetch("https://mock/a.pdf", &html, None).unwrap_err();
+        assert_eq!(error.to_string(), "[FETCH ERROR] failed to fetch the document at: https://mock/a.pdf, the last status is 200 after 1 attempt(s): the content type 'application/pdf' is not accepted for the html document");
+        assert_eq!(fetcher.fetch("https://mock/a.pdf", &RequestOptions::default(), None).unwrap(), "%PDF-1.4");
+        let error = fetcher.fetch("https://mock/large", &html, None).unwrap_err();
+        assert!(error.to_string().ends_with("the response is larger than the limit of 8 bytes"));
+        assert_eq!(fetcher.requests().len(), 3);
+    }
+}
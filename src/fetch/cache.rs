@@ -0,0 +1,131 @@
+use crate::document::{CommonError, ParseError};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+static NEXT_TEMP_FILE: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct CacheOptions {
+    pub dir: PathBuf,
+    /// How long a cached document is used without asking the server.
+    pub ttl: Duration,
+    /// Whether to fetch all the documents again, ignoring the cached ones.
+    pub refresh: bool
+}
+
+/// A cached response, with the validators to revalidate it by a conditional request. The body is
+/// kept as it was received, and is decoded each time it is taken, so that the encoding of the
+/// request applies to the cached documents too.
+#[derive(Debug,Clone,PartialEq)]
+pub struct CacheEntry {
+    pub stored_at: SystemTime,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>
+}
+
+impl CacheEntry {
+    pub fn is_fresh(&self, ttl: Duration) -> bool {
+        self.stored_at.elapsed().map(|age| age < ttl).unwrap_or(false)
+    }
+}
+
+/// The on-disk cache of the responses, one JSON file for each request in the cache directory.
+#[derive(Debug)]
+pub struct DiskCache {
+    options: CacheOptions
+}
+
+impl DiskCache {
+    pub fn new(options: CacheOptions) -> Self {
+        DiskCache{options}
+    }
+
+    pub fn options(&self) -> &CacheOptions {
+        &self.options
+    }
+
+    /// Takes the cached response of the request, which is identified by the key. A missing or
+    /// broken cache file is the same as no cached response.
+    pub fn load(&self, key: &str) -> Option<CacheEntry> {
+        let text = std::fs::read_to_string(self.path(key)).ok()?;
+        let value: Value = serde_json::from_str(&text).ok()?;
+        if value["key"].as_str() != Some(key) {
+            return None;
+        }
+        let optional = |name: &str| value[name].as_str().map(String::from);
+        Some(CacheEntry{
+            stored_at: UNIX_EPOCH + Duration::from_secs(value["stored_at"].as_u64()?),
+            etag: optional("etag"),
+            last_modified: optional("last_modified"),
+            content_type: optional("content_type"),
+            body: from_hex(value["body"].as_str()?)?
+        })
+    }
+
+    pub fn store(&self, key: &str, entry: &CacheEntry) -> Result<(), CommonError> {
+        let to_error = |e: std::io::Error| Box::new(ParseError::new_str(format!("failed to write the cache {}: {}", self.options.dir.display(), e)));
+        std::fs::create_dir_all(&self.options.dir).map_err(to_error)?;
+        let value = json!({
+            "key": key,
+            "stored_at": entry.stored_at.duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0),
+            "etag": entry.etag,
+            "last_modified": entry.last_modified,
+            "content_type": entry.content_type,
+            "body": to_hex(&entry.body)
+        });
+        // The file is written aside and moved in place, so that a concurrent fetch never reads a half-written file.
+        let path = self.path(key);
+        let temp_path = path.with_extension(format!("{}-{}.tmp", std::process::id(), NEXT_TEMP_FILE.fetch_add(1, Ordering::SeqCst)));
+        std::fs::write(&temp_path, value.to_string()).map_err(to_error)?;
+        std::fs::rename(&temp_path, &path).map_err(to_error)?;
+        Ok(())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.options.dir.join(format!("{:016x}.json", fnv1a(key)))
+    }
+}
+
+/// The FNV-1a hash, which unlike the hasher of the standard library is stable between the runs and the versions.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// The body is written in hex, because it may not be a valid UTF-8 text.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|idx| text.get(idx..idx + 2).and_then(|x| u8::from_str_radix(x, 16).ok())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheEntry, CacheOptions, DiskCache, DEFAULT_CACHE_TTL};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_store_and_load() {
+        let dir = std::env::temp_dir().join(format!("dessert3-cache-test-{}", std::process::id()));
+        let cache = DiskCache::new(CacheOptions{dir: dir.clone(), ttl: DEFAULT_CACHE_TTL, refresh: false});
+        assert_eq!(cache.load("GET https://mock"), None);
+        let entry = CacheEntry{stored_at: UNIX_EPOCH + Duration::from_secs(1_600_000_000), etag: Some(String::from("\"v1\"")), last_modified: None,
+            content_type: Some(String::from("text/html; charset=Shift_JIS")), body: b"<p>\x93\xfa\x96\x7b</p>".to_vec()};
+        cache.store("GET https://mock", &entry).unwrap();
+        assert_eq!(cache.load("GET https://mock"), Some(entry.clone()));
+        assert_eq!(cache.load("GET https://mock/other"), None);
+        assert!(!entry.is_fresh(DEFAULT_CACHE_TTL));
+        assert!(CacheEntry{stored_at: SystemTime::now(), ..entry}.is_fresh(DEFAULT_CACHE_TTL));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
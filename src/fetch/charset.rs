@@ -0,0 +1,70 @@
+use crate::document::{CommonError, ParseError};
+use encoding_rs::{Encoding, UTF_8};
+use regex::bytes::Regex;
+use std::sync::OnceLock;
+
+/// How many bytes of the document are scanned for the `<meta>` declaring the charset.
+const META_SCAN_LIMIT: usize = 1024;
+
+/// Looks up the encoding by its label, e.g. `Shift_JIS`, `windows-1252` or `latin1`.
+pub fn encoding_for_label(label: &str) -> Result<&'static Encoding, CommonError> {
+    Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| Box::new(ParseError::new_str(format!("unknown encoding: {}", label))) as CommonError)
+}
+
+/// Decodes the body of the response. The encoding is the override if it is given, otherwise it is
+/// detected from the byte order mark, the charset of the `Content-Type`, and the `<meta>` of the
+/// document in turn, and UTF-8 by default.
+pub fn decode(body: &[u8], content_type: Option<&str>, encoding_override: Option<&str>) -> Result<String, CommonError> {
+    let (encoding, bom_length) = match encoding_override {
+        Some(label) => (encoding_for_label(label)?, 0),
+        None => match Encoding::for_bom(body) {
+            Some((encoding, bom_length)) => (encoding, bom_length),
+            None => (content_type.and_then(content_type_charset).or_else(|| meta_charset(body)).unwrap_or(UTF_8), 0)
+        }
+    };
+    let (text, _) = encoding.decode_without_bom_handling(&body[bom_length..]);
+    Ok(text.into_owned())
+}
+
+/// The encoding of the charset parameter of the `Content-Type`, e.g. `text/html; charset=Shift_JIS`.
+fn content_type_charset(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("charset") {
+            Encoding::for_label(value.trim().trim_matches(|c| c == '"' || c == '\'').as_bytes())
+        } else {
+            None
+        }
+    })
+}
+
+/// The encoding declared by `<meta charset="...">` or `<meta http-equiv="Content-Type" content="...; charset=...">`
+/// at the beginning of the document. A declared UTF-16 is taken as UTF-8, because the document is
+/// not in UTF-16 if its `<meta>` can be read as ASCII.
+fn meta_charset(body: &[u8]) -> Option<&'static Encoding> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| Regex::new(r#"(?i-u)<meta\s[^>]*charset\s*=\s*["']?\s*([a-z0-9_:.-]+)"#).unwrap());
+    let head = &body[..body.len().min(META_SCAN_LIMIT)];
+    let label = pattern.captures(head)?.get(1)?.as_bytes();
+    Encoding::for_label(label).map(|encoding| encoding.output_encoding())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+
+    #[test]
+    fn test_decode() {
+        let shift_jis = b"<html><head><meta charset=\"Shift_JIS\"></head><p>\x93\xfa\x96\x7b</p></html>";
+        assert_eq!(decode(shift_jis, Some("text/html"), None).unwrap(), "<html><head><meta charset=\"Shift_JIS\"></head><p>日本</p></html>");
+        let windows_1252 = b"<meta http-equiv='Content-Type' content='text/html; charset=windows-1252'><p>caf\xe9</p>";
+        assert!(decode(windows_1252, None, None).unwrap().ends_with("<p>café</p>"));
+        assert_eq!(decode(b"caf\xe9", Some("text/html; charset=\"ISO-8859-1\""), None).unwrap(), "café");
+        assert_eq!(decode("café".as_bytes(), None, None).unwrap(), "café");
+        // The byte order mark wins over the Content-Type, and the override wins over all.
+        assert_eq!(decode(b"\xef\xbb\xbfcaf\xc3\xa9", Some("text/html; charset=windows-1252"), None).unwrap(), "café");
+        assert_eq!(decode(b"caf\xe9", Some("text/html; charset=utf-8"), Some("latin1")).unwrap(), "café");
+        assert_eq!(decode(b"<meta charset=utf-16><p>\xc3\xa9</p>", None, None).unwrap(), "<meta charset=utf-16><p>é</p>");
+        assert!(decode(b"", None, Some("klingon")).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::document::{CommonError, ParseError};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+#[derive(Debug,Clone,PartialEq)]
+struct Cookie {
+    domain: String,
+    include_subdomains: bool,
+    path: String,
+    secure: bool,
+    http_only: bool,
+    /// The expiry time in seconds since the unix epoch, `0` for a session cookie.
+    expires: u64,
+    name: String,
+    value: String
+}
+
+impl Cookie {
+    fn matches(&self, url: &Url, now: u64) -> bool {
+        let host = url.host_str().unwrap_or("").to_lowercase();
+        let domain_matched = host == self.domain || (self.include_subdomains && host.ends_with(&format!(".{}", self.domain)));
+        let path_matched = path_matches(url.path(), &self.path);
+        let scheme_matched = !self.secure || url.scheme() == "https";
+        domain_matched && path_matched && scheme_matched && !self.is_expired(now)
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+}
+
+/// The cookies in the Netscape cookie file format, which is used by curl and wget. The cookies
+/// set by the responses are kept in the jar, and written back to the file by `save`.
+#[derive(Debug)]
+pub struct CookieJar {
+    path: Option<PathBuf>,
+    cookies: Mutex<Vec<Cookie>>
+}
+
+impl CookieJar {
+    /// Loads the cookies from the file. The jar is empty if the file does not exist yet.
+    pub fn load(path: &Path) -> Result<Self, CommonError> {
+        let text = if path.exists() {
+            std::fs::read_to_string(path).map_err(|e| Box::new(ParseError::new_str(format!("failed to read the cookie jar {}: {}", path.display(), e))))?
+        } else {
+            String::new()
+        };
+        Ok(Self::from_text(&text, Some(path.to_path_buf())))
+    }
+
+    pub fn from_text(text: &str, path: Option<PathBuf>) -> Self {
+        let cookies = text.lines().filter_map(|line| {
+            let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                Some(line) => (line, true),
+                None => (line, false)
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 7 {
+                return None;
+            }
+            Some(Cookie{
+                domain: fields[0].trim_start_matches('.').to_lowercase(),
+                include_subdomains: fields[1].eq_ignore_ascii_case("TRUE"),
+                path: String::from(fields[2]),
+                secure: fields[3].eq_ignore_ascii_case("TRUE"),
+                http_only,
+                expires: fields[4].parse().unwrap_or(0),
+                name: String::from(fields[5]),
+                value: String::from(fields[6])
+            })
+        }).collect();
+        CookieJar{path, cookies: Mutex::new(cookies)}
+    }
+
+    /// Returns the value of the `Cookie` header for the url, the cookies with longer paths first.
+    pub fn header(&self, url: &Url) -> Option<String> {
+        let now = now();
+        let cookies = self.cookies.lock().unwrap();
+        let mut matched: Vec<&Cookie> = cookies.iter().filter(|cookie| cookie.matches(url, now)).collect();
+        matched.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        let pairs: Vec<String> = matched.iter().map(|cookie| format!("{}={}", cookie.name, cookie.value)).collect();
+        if pairs.is_empty() {
+            None
+        } else {
+            Some(pairs.join("; "))
+        }
+    }
+
+    /// Keeps the cookie of the `Set-Cookie` header of the response from the url. A cookie whose
+    /// `Domain` is not the host of the url or one of its parent domains is rejected, and so is a
+    /// `Domain` on an IP address or on a top-level domain like `com`.
+    pub fn store(&self, url: &Url, set_cookie: &str) {
+        let host = url.host_str().unwrap_or("").to_lowercase();
+        let mut parts = set_cookie.split(';');
+        let (name, value) = match parts.next().and_then(|pair| pair.split_once('=')) {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return
+        };
+        let mut cookie = Cookie{
+            domain: host.clone(),
+            include_subdomains: false,
+            path: String::from("/"),
+            secure: false,
+            http_only: false,
+            expires: 0,
+            name: String::from(name),
+            value: String::from(value)
+        };
+        // `Max-Age` takes precedence over `Expires`, whatever their order.
+        let mut max_age_set = false;
+        for attribute in parts {
+            let (key, value) = match attribute.split_once('=') {
+                Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+                None => (attribute.trim().to_lowercase(), "")
+            };
+            match key.as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_lowercase();
+                    if domain == host && host.parse::<IpAddr>().is_ok() {
+                        continue;
+                    }
+                    if !domain_matches(&host, &domain) {
+                        return;
+                    }
+                    cookie.domain = domain;
+                    cookie.include_subdomains = true;
+                },
+                "path" if value.starts_with('/') => cookie.path = String::from(value),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "max-age" => if let Ok(max_age) = value.parse::<i64>() {
+                    cookie.expires = if max_age > 0 { now() + max_age as u64 } else { 1 };
+                    max_age_set = true;
+                },
+                "expires" if !max_age_set => if let Some(expires) = parse_cookie_date(value) {
+                    cookie.expires = expires.max(1);
+                },
+                _ => {}
+            }
+        }
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|x| !(x.domain == cookie.domain && x.path == cookie.path && x.name == cookie.name));
+        cookies.push(cookie);
+    }
+
+    /// Writes the cookies, except the expired ones, back to the file of the jar.
+    pub fn save(&self) -> Result<(), CommonError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        std::fs::write(path, self.to_text()).map_err(|e| Box::new(ParseError::new_str(format!("failed to write the cookie jar {}: {}", path.display(), e))))?;
+        Ok(())
+    }
+
+    fn to_text(&self) -> String {
+        let now = now();
+        let mut text = String::from("# Netscape HTTP Cookie File\n");
+        for cookie in self.cookies.lock().unwrap().iter().filter(|cookie| !cookie.is_expired(now)) {
+            let bool_text = |x: bool| if x { "TRUE" } else { "FALSE" };
+            let domain = if cookie.include_subdomains { format!(".{}", cookie.domain) } else { cookie.domain.clone() };
+            text.push_str(&format!("{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.http_only { "#HttpOnly_" } else { "" }, domain, bool_text(cookie.include_subdomains),
+                cookie.path, bool_text(cookie.secure), cookie.expires, cookie.name, cookie.value));
+        }
+        text
+    }
+}
+
+/// Whether the host is the domain or a subdomain of it, where the domain is neither an IP address
+/// nor a top-level domain.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    if host.parse::<IpAddr>().is_ok() || domain.parse::<IpAddr>().is_ok() || !domain.contains('.') {
+        return false;
+    }
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+/// The path-match of RFC 6265, e.g. the path `/docs` matches `/docs` and `/docs/a` but not `/docsearch`.
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    match request_path.strip_prefix(cookie_path) {
+        Some(rest) => rest.is_empty() || cookie_path.ends_with('/') || rest.starts_with('/'),
+        None => false
+    }
+}
+
+/// Parses the date of `Expires` into seconds since the unix epoch, per the lenient algorithm of
+/// RFC 6265, e.g. `Wed, 21 Oct 2015 07:28:00 GMT` or `Wed, 21-Oct-15 07:28:00 GMT`.
+fn parse_cookie_date(value: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+    let (mut time, mut day, mut month, mut year) = (None, None, None, None);
+    let is_delimiter = |c: char| !(c.is_ascii_alphanumeric() || c == ':');
+    for token in value.split(is_delimiter).filter(|x| !x.is_empty()) {
+        let digits = |text: &str| -> Option<u64> { if !text.is_empty() && text.len() <= 4 && text.chars().all(|c| c.is_ascii_digit()) { text.parse().ok() } else { None } };
+        if time.is_none() && token.contains(':') {
+            let parts: Vec<Option<u64>> = token.split(':').map(digits).collect();
+            if let [Some(hour), Some(minute), Some(second)] = parts[..] {
+                time = Some((hour, minute, second));
+                continue;
+            }
+        }
+        let number = digits(token.trim_end_matches(|c: char| c.is_ascii_alphabetic()));
+        if day.is_none() && token.len() <= 2 && number.is_some() {
+            day = number;
+        } else if month.is_none() && token.len() >= 3 && MONTHS.contains(&token[..3].to_lowercase().as_str()) {
+            month = MONTHS.iter().position(|x| *x == token[..3].to_lowercase()).map(|x| x as u64 + 1);
+        } else if year.is_none() && (2..=4).contains(&token.len()) && number.is_some() {
+            year = number.map(|x| match x {
+                0..=69 => x + 2000,
+                70..=99 => x + 1900,
+                _ => x
+            });
+        }
+    }
+    let ((hour, minute, second), day, month, year) = (time?, day?, month?, year?);
+    if !(1..=31).contains(&day) || year < 1970 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    // The days since the unix epoch of the civil date, by the algorithm of Howard Hinnant.
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y / 400;
+    let day_of_era = 365 * (y % 400) + (y % 400) / 4 - (y % 400) / 100 + (153 * m + 2) / 5 + day - 1;
+    let days = era * 146097 + day_of_era - 719468;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cookie_date, CookieJar};
+    use url::Url;
+
+    #[test]
+    fn test_header() {
+        let jar = CookieJar::from_text("# Netscape HTTP Cookie File\n.example.com\tTRUE\t/\tFALSE\t0\ta\t1\n#HttpOnly_www.example.com\tFALSE\t/docs\tTRUE\t0\tb\t2\nexample.com\tTRUE\t/\tFALSE\t1\texpired\t3\n", None);
+        assert_eq!(jar.header(&Url::parse("https://www.example.com/docs/a").unwrap()).unwrap(), "b=2; a=1");
+        assert_eq!(jar.header(&Url::parse("http://www.example.com/docs/a").unwrap()).unwrap(), "a=1");
+        assert_eq!(jar.header(&Url::parse("https://example.com/").unwrap()).unwrap(), "a=1");
+        assert_eq!(jar.header(&Url::parse("https://example.org/").unwrap()), None);
+    }
+
+    #[test]
+    fn test_store() {
+        let jar = CookieJar::from_text("", None);
+        let url = Url::parse("https://www.example.com/").unwrap();
+        jar.store(&url, "session=abc; Domain=.example.com; HttpOnly");
+        jar.store(&url, "lang=fr; Path=/fr; Max-Age=3600");
+        jar.store(&url, "session=def; Domain=example.com");
+        assert_eq!(jar.header(&Url::parse("https://api.example.com/").unwrap()).unwrap(), "session=def");
+        assert_eq!(jar.header(&Url::parse("https://www.example.com/fr/").unwrap()).unwrap(), "lang=fr; session=def");
+        jar.store(&url, "lang=; Path=/fr; Max-Age=0");
+        assert_eq!(jar.header(&Url::parse("https://www.example.com/fr/").unwrap()).unwrap(), "session=def");
+        assert!(jar.to_text().contains("\n.example.com\tTRUE\t/\tFALSE\t0\tsession\tdef\n"));
+        // The path matches on a `/` boundary only.
+        jar.store(&url, "doc=1; Path=/docs");
+        assert_eq!(jar.header(&Url::parse("https://www.example.com/docs/a").unwrap()).unwrap(), "doc=1; session=def");
+        assert_eq!(jar.header(&Url::parse("https://www.example.com/docsearch").unwrap()).unwrap(), "session=def");
+    }
+
+    #[test]
+    fn test_store_foreign_domain() {
+        let jar = CookieJar::from_text("", None);
+        jar.store(&Url::parse("https://evil.example/").unwrap(), "session=stolen; Domain=bank.example");
+        jar.store(&Url::parse("https://evil.example/").unwrap(), "tld=1; Domain=example");
+        jar.store(&Url::parse("https://www.bank.example/").unwrap(), "site=1; Domain=.example");
+        jar.store(&Url::parse("https://notbank.example/").unwrap(), "suffix=1; Domain=bank.example");
+        jar.store(&Url::parse("http://192.168.1.10/").unwrap(), "ip=1; Domain=168.1.10");
+        jar.store(&Url::parse("http://192.168.1.10/").unwrap(), "own=1; Domain=192.168.1.10");
+        assert_eq!(jar.header(&Url::parse("https://bank.example/").unwrap()), None);
+        assert_eq!(jar.header(&Url::parse("https://evil.example/").unwrap()), None);
+        assert_eq!(jar.header(&Url::parse("http://192.168.1.10/").unwrap()).unwrap(), "own=1");
+    }
+
+    #[test]
+    fn test_store_expires() {
+        assert_eq!(parse_cookie_date("Wed, 21 Oct 2015 07:28:00 GMT"), Some(1445412480));
+        assert_eq!(parse_cookie_date("Wednesday, 21-Oct-15 07:28:00 GMT"), Some(1445412480));
+        assert_eq!(parse_cookie_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_cookie_date("tomorrow"), None);
+        let jar = CookieJar::from_text("", None);
+        let url = Url::parse("https://www.example.com/").unwrap();
+        jar.store(&url, "old=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT");
+        jar.store(&url, "new=2; Expires=Fri, 01 Jan 2100 00:00:00 GMT");
+        jar.store(&url, "both=3; Max-Age=3600; Expires=Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(jar.header(&url).unwrap(), "new=2; both=3");
+        assert!(jar.to_text().contains("\tFALSE\t4102444800\tnew\t2\n"));
+        assert!(!jar.to_text().contains("old"));
+    }
+}
@@ -0,0 +1,153 @@
+use std::str::FromStr;
+
+pub const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// How many bytes at the beginning of the body are sniffed for a binary response.
+const BINARY_SNIFF_LIMIT: usize = 1024;
+
+/// The format that a token reads the document as, which decides the content types it accepts.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum DocFormat {
+    /// The HTML document of `css`, `meta`, `jsonld`, `table` and the `loop` of `selector`.
+    Html,
+    /// The raw text of `regex` and the `loop` of `pattern`.
+    Text
+}
+
+impl FromStr for DocFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "html" => Ok(DocFormat::Html),
+            "text" => Ok(DocFormat::Text),
+            _ => Err(format!("unknown document format: {}, expect 'html' or 'text'", value))
+        }
+    }
+}
+
+impl std::fmt::Display for DocFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DocFormat::Html => write!(f, "html"),
+            DocFormat::Text => write!(f, "text")
+        }
+    }
+}
+
+/// The limits of the responses, which keep a large or binary response away from the parsers.
+#[derive(Debug,Clone,PartialEq)]
+pub struct FetchLimits {
+    pub max_body_size: usize,
+    /// The content types accepted by each format, where `*` matches any characters, e.g. `text/*`.
+    pub content_types: Vec<(DocFormat, Vec<String>)>
+}
+
+impl Default for FetchLimits {
+    fn default() -> Self {
+        let types = |types: &[&str]| types.iter().map(|x| String::from(*x)).collect();
+        FetchLimits{
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            content_types: vec![
+                (DocFormat::Html, types(&["text/html", "application/xhtml+xml", "text/xml", "application/xml"])),
+                (DocFormat::Text, types(&["text/*", "application/json", "application/*+json", "application/xml", "application/*+xml", "application/javascript", "application/x-javascript", "application/ecmascript"]))
+            ]
+        }
+    }
+}
+
+impl FetchLimits {
+    /// Replaces the content types accepted by the format.
+    pub fn set_content_types(&mut self, format: DocFormat, content_types: Vec<String>) {
+        self.content_types.retain(|(x, _)| *x != format);
+        self.content_types.push((format, content_types));
+    }
+
+    /// Checks the response for the format, and returns the reason if it is refused. A response
+    /// without the `Content-Type` is accepted unless it looks binary.
+    pub fn check(&self, format: Option<DocFormat>, content_type: Option<&str>, body: &[u8]) -> Result<(), String> {
+        if body.len() > self.max_body_size {
+            return Err(format!("the response is larger than the limit of {} bytes", self.max_body_size));
+        }
+        let format = match format {
+            Some(format) => format,
+            None => return Ok(())
+        };
+        self.check_content_type(Some(format), content_type)?;
+        if is_binary(body) {
+            return Err(format!("the response is binary{}, not a document of the {} format", mime(content_type).map(|x| format!("({})", x)).unwrap_or_default(), format));
+        }
+        Ok(())
+    }
+
+    /// Checks the `Content-Type` of the response for the format, e.g. before its body is read.
+    pub fn check_content_type(&self, format: Option<DocFormat>, content_type: Option<&str>) -> Result<(), String> {
+        if let (Some(format), Some(mime)) = (format, mime(content_type)) {
+            let accepted = self.content_types.iter()
+                .filter(|(x, _)| *x == format)
+                .flat_map(|(_, types)| types.iter())
+                .any(|pattern| matches(&pattern.to_lowercase(), &mime));
+            if !accepted {
+                return Err(format!("the content type '{}' is not accepted for the {} document", mime, format));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The media type of the `Content-Type`, without its parameters.
+fn mime(content_type: Option<&str>) -> Option<String> {
+    content_type.map(|x| x.split(';').next().unwrap_or("").trim().to_lowercase()).filter(|x| !x.is_empty())
+}
+
+/// Whether the body looks binary, that is, it has a NUL byte and it is not UTF-16.
+fn is_binary(body: &[u8]) -> bool {
+    let head = &body[..body.len().min(BINARY_SNIFF_LIMIT)];
+    !head.starts_with(&[0xff, 0xfe]) && !head.starts_with(&[0xfe, 0xff]) && head.contains(&0)
+}
+
+fn matches(pattern: &str, mime: &str) -> bool {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => mime.len() >= prefix.len() + suffix.len() && mime.starts_with(prefix) && mime.ends_with(suffix),
+        None => pattern == mime
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DocFormat, FetchLimits};
+
+    #[test]
+    fn test_check() {
+        let limits = FetchLimits::default();
+        assert!(limits.check(Some(DocFormat::Html), Some("text/html; charset=utf-8"), b"<p>hi</p>").is_ok());
+        assert!(limits.check(Some(DocFormat::Html), None, b"<p>hi</p>").is_ok());
+        assert_eq!(limits.check(Some(DocFormat::Html), Some("application/pdf"), b"%PDF-1.4").unwrap_err(), "the content type 'application/pdf' is not accepted for the html document");
+        assert!(limits.check(Some(DocFormat::Text), Some("application/ld+json"), b"{}").is_ok());
+        assert!(limits.check(Some(DocFormat::Text), Some("text/javascript"), b"var x;").is_ok());
+        assert!(limits.check(Some(DocFormat::Html), Some("text/javascript"), b"var x;").is_err());
+        assert_eq!(limits.check(Some(DocFormat::Html), None, b"\x89PNG\r\n\x1a\n\x00\x00").unwrap_err(), "the response is binary, not a document of the html format");
+        assert!(limits.check(Some(DocFormat::Html), None, b"\xff\xfe<\x00p\x00>\x00").is_ok());
+        assert!(limits.check(None, Some("image/png"), b"\x00").is_ok());
+        let limits = FetchLimits{max_body_size: 4, ..Default::default()};
+        assert_eq!(limits.check(None, None, b"<p>hi</p>").unwrap_err(), "the response is larger than the limit of 4 bytes");
+    }
+
+    #[test]
+    fn test_check_content_type() {
+        let limits = FetchLimits::default();
+        assert!(limits.check_content_type(Some(DocFormat::Html), Some("Text/HTML; charset=utf-8")).is_ok());
+        assert_eq!(limits.check_content_type(Some(DocFormat::Html), Some("application/pdf")).unwrap_err(), "the content type 'application/pdf' is not accepted for the html document");
+        assert!(limits.check_content_type(Some(DocFormat::Html), None).is_ok());
+        assert!(limits.check_content_type(None, Some("application/pdf")).is_ok());
+    }
+
+    #[test]
+    fn test_set_content_types() {
+        let mut limits = FetchLimits::default();
+        limits.set_content_types(DocFormat::Html, vec![String::from("text/plain")]);
+        assert!(limits.check(Some(DocFormat::Html), Some("text/plain"), b"<p>hi</p>").is_ok());
+        assert!(limits.check(Some(DocFormat::Html), Some("text/html"), b"<p>hi</p>").is_err());
+        assert!("pdf".parse::<DocFormat>().is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How politely the documents are requested from each host.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct PolitenessOptions {
+    /// The minimum delay between the starts of two requests to the same host.
+    pub min_delay: Option<Duration>,
+    pub max_requests_per_second: Option<f64>,
+    pub max_connections_per_host: Option<usize>,
+    /// Whether to skip the urls disallowed by the robots.txt of the host.
+    pub robots: bool,
+    /// The local robots.txt used for all the hosts instead of the ones of the hosts, e.g. for tests.
+    pub robots_file: Option<PathBuf>
+}
+
+impl PolitenessOptions {
+    /// The interval between the starts of two requests to the same host.
+    fn interval(&self) -> Duration {
+        let rate_interval = self.max_requests_per_second
+            .filter(|rps| *rps > 0.0)
+            .and_then(|rps| Duration::try_from_secs_f64(1.0 / rps).ok())
+            .unwrap_or(Duration::ZERO);
+        self.min_delay.unwrap_or(Duration::ZERO).max(rate_interval)
+    }
+}
+
+#[derive(Debug)]
+struct HostState {
+    /// The earliest time that the next request can start.
+    next_start: Instant,
+    connections: usize
+}
+
+/// The politeness layer under the fetcher, which spaces out the requests to each host and bounds
+/// the connections to each host.
+#[derive(Debug)]
+pub struct Politeness {
+    options: PolitenessOptions,
+    hosts: Mutex<HashMap<String, HostState>>,
+    released: Condvar
+}
+
+/// A connection to the host, which is given back when it is dropped.
+pub struct HostPermit<'a> {
+    politeness: &'a Politeness,
+    host: String
+}
+
+impl<'a> Drop for HostPermit<'a> {
+    fn drop(&mut self) {
+        let mut hosts = self.politeness.hosts.lock().unwrap();
+        if let Some(state) = hosts.get_mut(&self.host) {
+            state.connections -= 1;
+        }
+        self.politeness.released.notify_all();
+    }
+}
+
+impl Politeness {
+    pub fn new(options: PolitenessOptions) -> Self {
+        Politeness{options, hosts: Mutex::new(HashMap::new()), released: Condvar::new()}
+    }
+
+    pub fn options(&self) -> &PolitenessOptions {
+        &self.options
+    }
+
+    /// Takes a connection to the host, waiting for one if all of them are taken. Returns the
+    /// connection and the delay to wait before the request starts.
+    pub fn acquire(&self, host: &str) -> (HostPermit<'_>, Duration) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(max_connections) = self.options.max_connections_per_host {
+            while hosts.get(host).map(|state| state.connections >= max_connections.max(1)).unwrap_or(false) {
+                hosts = self.released.wait(hosts).unwrap();
+            }
+        }
+        let now = Instant::now();
+        let state = hosts.entry(String::from(host)).or_insert(HostState{next_start: now, connections: 0});
+        let start = state.next_start.max(now);
+        state.next_start = start + self.options.interval();
+        state.connections += 1;
+        (HostPermit{politeness: self, host: String::from(host)}, start - now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Politeness, PolitenessOptions};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_interval() {
+        let politeness = Politeness::new(PolitenessOptions{min_delay: Some(Duration::from_millis(100)), max_requests_per_second: Some(5.0), ..Default::default()});
+        let (_, first) = politeness.acquire("a.com");
+        let (_, second) = politeness.acquire("a.com");
+        let (_, other_host) = politeness.acquire("b.com");
+        assert_eq!(first, Duration::ZERO);
+        assert!(second > Duration::from_millis(150) && second <= Duration::from_millis(200), "{:?}", second);
+        assert_eq!(other_host, Duration::ZERO);
+        assert_eq!(Politeness::new(PolitenessOptions::default()).acquire("a.com").1, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let politeness = Arc::new(Politeness::new(PolitenessOptions{max_connections_per_host: Some(1), ..Default::default()}));
+        let permit = politeness.acquire("a.com");
+        let waiting = {
+            let politeness = politeness.clone();
+            std::thread::spawn(move || { politeness.acquire("a.com"); })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+        drop(permit);
+        waiting.join().unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+
+/// A check of the url of a redirect, which refuses it by an error message.
+#[cfg(not(test))]
+pub type RedirectGuard = Guard<dyn Fn(&url::Url) -> Result<(), String> + Send + Sync>;
+/// A check of an address that the host of a url resolves to, which refuses it by an error message.
+pub type AddressGuard = Guard<dyn Fn(&url::Url, IpAddr) -> Result<(), String> + Send + Sync>;
+
+/// A check given by the caller of the fetcher, e.g. by the sandbox.
+pub struct Guard<F: ?Sized>(pub Arc<F>);
+
+impl<F: ?Sized> Clone for Guard<F> {
+    fn clone(&self) -> Self {
+        Guard(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> std::fmt::Debug for Guard<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Guard")
+    }
+}
+
+/// Resolves the host of the url, and checks all its addresses with the guard. The request is sent
+/// to these addresses only, so that the host can not resolve to another address when the request
+/// connects, e.g. by DNS rebinding.
+pub fn resolve_checked(url: &url::Url, guard: &AddressGuard) -> Result<Vec<SocketAddr>, String> {
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addresses: Vec<SocketAddr> = match url.host() {
+        Some(url::Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Some(url::Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        Some(url::Host::Domain(domain)) => (domain, port).to_socket_addrs()
+            .map_err(|e| format!("failed to resolve {}: {}", domain, e))?
+            .collect(),
+        None => return Err(format!("the url has no host: {}", url))
+    };
+    if addresses.is_empty() {
+        return Err(format!("the host of the url has no address: {}", url));
+    }
+    for address in &addresses {
+        (guard.0)(url, address.ip())?;
+    }
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_checked, AddressGuard, Guard};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_resolve_checked() {
+        let guard: AddressGuard = Guard(Arc::new(|_: &url::Url, ip: IpAddr| if ip.is_loopback() { Err(format!("refused {}", ip)) } else { Ok(()) }));
+        let resolve = |url: &str| resolve_checked(&url::Url::parse(url).unwrap(), &guard);
+        assert_eq!(resolve("http://8.8.8.8/"), Ok(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 80)]));
+        assert_eq!(resolve("https://8.8.8.8/").unwrap()[0].port(), 443);
+        assert_eq!(resolve("http://127.0.0.1:8080/"), Err(String::from("refused 127.0.0.1")));
+        assert_eq!(resolve("http://[::1]/"), Err(String::from("refused ::1")));
+        assert!(resolve("http://localhost/").is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime};
+
+pub const DEFAULT_RETRIES: u32 = 2;
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// The longest time to wait before a retry, no matter what the backoff or `Retry-After` says.
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(120);
+
+/// Whether a response of the status is worth retrying: too many requests, or a server error.
+pub fn is_retryable_status(status: u16) -> bool {
+    status == 429 || status >= 500
+}
+
+/// Whether the requests of the method are retried by default. The other requests, e.g. sending a
+/// form, may repeat their effect if they are sent again, so they are retried only by their own 'retries'.
+pub fn is_retried_by_default(method: &str) -> bool {
+    method == "GET" || method == "HEAD"
+}
+
+/// The exponential backoff before the retry after the attempt(1-based): the retry delay doubled for
+/// each attempt, with a random jitter between the half and the whole of it.
+pub fn backoff(retry_delay: Duration, attempt: u32) -> Duration {
+    let delay = retry_delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(MAX_RETRY_DELAY);
+    let jitter = random() % 1000;
+    delay / 2 + (delay / 2).mul_f64(jitter as f64 / 1000.0)
+}
+
+/// Parses the value of the `Retry-After` header, either the seconds to wait or the HTTP date to wait until.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => httpdate::parse_http_date(value).ok()?.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)
+    };
+    Some(delay.min(MAX_RETRY_DELAY))
+}
+
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, parse_retry_after, is_retryable_status, MAX_RETRY_DELAY};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_backoff() {
+        for attempt in 1..4 {
+            let delay = backoff(Duration::from_secs(1), attempt);
+            let full = Duration::from_secs(1 << (attempt - 1));
+            assert!(delay >= full / 2 && delay <= full, "{:?} of attempt {}", delay, attempt);
+        }
+        assert!(backoff(Duration::from_secs(1), 100) <= MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after(" 3 "), Some(Duration::from_secs(3)));
+        assert_eq!(parse_retry_after("86400"), Some(MAX_RETRY_DELAY));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO));
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(10));
+        assert!(parse_retry_after(&later).unwrap() > Duration::from_secs(5));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_is_retryable_status() {
+        assert!(is_retryable_status(429));
+        assert!(is_retryable_status(503));
+        assert!(!is_retryable_status(404));
+    }
+}
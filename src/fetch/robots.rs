@@ -0,0 +1,132 @@
+/// The rules of a robots.txt for a user agent.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct Robots {
+    /// The path patterns, and whether they are allowed.
+    rules: Vec<(String, bool)>
+}
+
+impl Robots {
+    /// Parses the robots.txt, taking the group of the user agent, or the group of `*` if there is
+    /// no group for the user agent.
+    pub fn parse(text: &str, user_agent: &str) -> Self {
+        let user_agent = user_agent.split('/').next().unwrap_or("").trim().to_lowercase();
+        let mut agent_rules = None;
+        let mut default_rules = None;
+        // The user agents of the current group, and whether the rules of the group have begun.
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+        let mut rules: Vec<(String, bool)> = Vec::new();
+        let mut finish_group = |agents: &[String], rules: &[(String, bool)]| {
+            if agents.iter().any(|agent| agent != "*" && !user_agent.is_empty() && user_agent.contains(agent.as_str())) {
+                agent_rules.get_or_insert_with(Vec::new).extend(rules.iter().cloned());
+            }
+            if agents.iter().any(|agent| agent == "*") {
+                default_rules.get_or_insert_with(Vec::new).extend(rules.iter().cloned());
+            }
+        };
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+                None => continue
+            };
+            match key.as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        finish_group(&agents, &rules);
+                        agents.clear();
+                        rules.clear();
+                        in_rules = false;
+                    }
+                    agents.push(value.to_lowercase());
+                },
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    // An empty `Disallow` allows everything, which is the same as no rule.
+                    if !value.is_empty() {
+                        rules.push((String::from(value), key == "allow"));
+                    }
+                },
+                _ => {}
+            }
+        }
+        finish_group(&agents, &rules);
+        Robots{rules: agent_rules.or(default_rules).unwrap_or_default()}
+    }
+
+    /// Whether the path(with the query) is allowed. The longest matched rule wins, and `Allow` wins a tie.
+    pub fn is_allowed(&self, path: &str) -> bool {
+        let mut matched: Option<(usize, bool)> = None;
+        for (pattern, allowed) in &self.rules {
+            if Self::matches(pattern, path) {
+                let better = match matched {
+                    Some((length, matched_allowed)) => pattern.len() > length || (pattern.len() == length && *allowed && !matched_allowed),
+                    None => true
+                };
+                if better {
+                    matched = Some((pattern.len(), *allowed));
+                }
+            }
+        }
+        matched.map(|(_, allowed)| allowed).unwrap_or(true)
+    }
+
+    /// Matches the path with the pattern, where `*` matches any characters and a trailing `$` matches the end.
+    fn matches(pattern: &str, path: &str) -> bool {
+        let (pattern, anchored) = match pattern.strip_suffix('$') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false)
+        };
+        let parts: Vec<&str> = pattern.split('*').collect();
+        let mut rest = match path.strip_prefix(parts[0]) {
+            Some(rest) => rest,
+            None => return false
+        };
+        for (index, part) in parts.iter().enumerate().skip(1) {
+            if index == parts.len() - 1 && anchored {
+                return rest.ends_with(part);
+            }
+            match rest.find(part) {
+                Some(position) => rest = &rest[position + part.len()..],
+                None => return false
+            }
+        }
+        !anchored || rest.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Robots;
+
+    const ROBOTS: &str = "# comment
+User-agent: *
+Disallow: /private
+Allow: /private/public
+Disallow: /*.pdf$
+
+User-agent: BadBot
+User-agent: dessert3
+Disallow: /
+Allow: /reference
+";
+
+    #[test]
+    fn test_default_group() {
+        let robots = Robots::parse(ROBOTS, "Mozilla/5.0");
+        assert!(robots.is_allowed("/"));
+        assert!(!robots.is_allowed("/private/a"));
+        assert!(robots.is_allowed("/private/public/a"));
+        assert!(!robots.is_allowed("/docs/a.pdf"));
+        assert!(robots.is_allowed("/docs/a.pdf?page=1"));
+    }
+
+    #[test]
+    fn test_agent_group() {
+        let robots = Robots::parse(ROBOTS, "dessert3/0.1.0");
+        assert!(!robots.is_allowed("/"));
+        assert!(robots.is_allowed("/reference/province"));
+        assert!(Robots::parse("", "dessert3").is_allowed("/"));
+        assert!(Robots::parse("User-agent: *\nDisallow:\n", "dessert3").is_allowed("/"));
+    }
+}
@@ -1,8 +1,21 @@
 
+mod cli;
 mod document;
+mod fetch;
+mod repl;
+mod suggest;
 mod template_parser;
 
 use template_parser::builder::DocBuilder;
+use template_parser::output::{Output, WriteOutput};
+use template_parser::sandbox::Sandbox;
+use template_parser::{CommonError, ErrorKind};
+use fetch::{Fetcher, RequestOptions};
+use fetch::limits::DocFormat;
+use fetch::cookie::CookieJar;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
 
 #[cfg(test)]
 mod mock;
@@ -34,12 +47,230 @@ mod mock;
 /// ```
 ///
 fn main() {
-    let template = std::env::args().nth(1).expect("Expect template as a parameter");
-    let doc_builder = DocBuilder::new();
-    match template_parser::parse(&template, &doc_builder) {
-        Ok(output) => println!("{}", output),
-        Err(error) => println!("Error: {}", error)
- }
+    std::process::exit(run());
+}
+
+/// The exit codes of the command.
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_TEMPLATE: i32 = 3;
+const EXIT_FETCH: i32 = 4;
+const EXIT_MISSING_VALUE: i32 = 5;
+const EXIT_SANDBOX: i32 = 6;
+
+fn exit_code(kind: ErrorKind) -> i32 {
+    match kind {
+        ErrorKind::Template => EXIT_TEMPLATE,
+        ErrorKind::Fetch => EXIT_FETCH,
+        ErrorKind::MissingValue => EXIT_MISSING_VALUE,
+        ErrorKind::Sandbox => EXIT_SANDBOX,
+        ErrorKind::Other => EXIT_ERROR
+    }
+}
+
+/// Runs the command, and returns its exit code. The errors are printed to the stderr, so that they
+/// never end up in the output.
+fn run() -> i32 {
+    let cli = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(error) => {
+            eprintln!("Error: {}\n\n{}", error, cli::USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    if cli.trace {
+        log::set_logger(&TraceLogger).expect("the logger is set only once");
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+    let cache_options = cli.cache_options();
+    let mut fetcher = Fetcher::new(cli.request).with_limits(cli.limits).with_politeness(cli.politeness);
+    if let Some(cache_options) = cache_options {
+        fetcher = fetcher.with_cache(cache_options);
+    }
+    if let Some(cookie_jar) = &cli.cookie_jar {
+        match CookieJar::load(cookie_jar) {
+            Ok(cookie_jar) => fetcher = fetcher.with_cookie_jar(cookie_jar),
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                return EXIT_ERROR;
+            }
+        }
+    }
+    let deny_private_ips = cli.sandbox.deny_private_ips;
+    let sandbox = Arc::new(Sandbox::new(cli.sandbox));
+    let redirect_sandbox = sandbox.clone();
+    fetcher = fetcher.with_redirect_guard(move |url| redirect_sandbox.check_url(url).map_err(|e| e.to_string()));
+    if deny_private_ips {
+        let address_sandbox = sandbox.clone();
+        fetcher = fetcher.with_address_guard(move |url, ip| address_sandbox.check_address(url, ip).map_err(|e| e.to_string()));
+    }
+    let mut doc_builder = DocBuilder::new(fetcher).with_sandbox(sandbox).with_on_error(cli.on_error);
+    if let Some(concurrency) = cli.concurrency {
+        doc_builder = doc_builder.with_concurrency(concurrency);
+    }
+    match &cli.command {
+        cli::Command::Render => {},
+        cli::Command::Check(path) => {
+            let template = match read_template(path) {
+                Ok(template) => template,
+                Err(code) => return code
+            };
+            let problems = template_parser::check::check(&template, &doc_builder);
+            for problem in &problems {
+                println!("{}:{}", path.display(), problem);
+            }
+            if problems.is_empty() {
+                return 0;
+            }
+            eprintln!("{} problem(s) in the template", problems.len());
+            return EXIT_TEMPLATE;
+        },
+        cli::Command::Ast(path) => {
+            let template = match read_template(path) {
+                Ok(template) => template,
+                Err(code) => return code
+            };
+            return match template_parser::ast(&template, &doc_builder) {
+                Ok(nodes) if cli.json => {
+                    println!("{}", serde_json::to_string_pretty(&template_parser::ast::to_json(&nodes)).unwrap());
+                    0
+                },
+                Ok(nodes) => {
+                    print!("{}", template_parser::ast::tree(&nodes, &template));
+                    0
+                },
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    exit_code(ErrorKind::of(&error))
+                }
+            };
+        },
+        cli::Command::Repl(source) => {
+            let text = match read_page(source, &doc_builder) {
+                Ok(text) => text,
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    return exit_code(ErrorKind::of(&error));
+                }
+            };
+            let doc_builder = doc_builder.with_document(source, &text);
+            return match repl::run(source, &text, &doc_builder, std::io::stdin().lock(), &mut std::io::stdout()) {
+                Ok(_) => 0,
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    exit_code(ErrorKind::of(&error))
+                }
+            };
+        },
+        cli::Command::Suggest(source) => {
+            let examples = &cli.examples;
+            return match read_page(source, &doc_builder).and_then(|text| suggest::suggest(source, &text, examples)) {
+                Ok(suggestion) => {
+                    print!("{}", suggestion);
+                    0
+                },
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    exit_code(ErrorKind::of(&error))
+                }
+            };
+        }
+    }
+    let template = &cli.template;
+    let output = &cli.output;
+    if cli.plan {
+        return match template_parser::plan(template, &doc_builder) {
+            Ok(plan) => {
+                print!("{}", plan);
+                0
+            },
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                exit_code(ErrorKind::of(&error))
+            }
+        };
+    }
+    let provenance = match &cli.provenance {
+        Some(path) => match File::create(path) {
+            Ok(file) => Some(BufWriter::new(file)),
+            Err(error) => {
+                eprintln!("Error: {}: {}", path.display(), error);
+                return EXIT_ERROR;
+            }
+        },
+        None => None
+    };
+    let result = match output {
+        Some(path) => File::create(path).map_err(|e| e.into())
+            .and_then(|file| template_parser::render(template, &doc_builder, &mut with_provenance(WriteOutput::new(BufWriter::new(file)), provenance))),
+        None => {
+            let mut stdout = with_provenance(WriteOutput::new(BufWriter::new(std::io::stdout())), provenance);
+            template_parser::render(template, &doc_builder, &mut stdout).and_then(|_| stdout.write_str("\n")).and_then(|_| stdout.flush())
+        }
+    };
+    let mut code = 0;
+    if let Err(error) = result {
+        eprintln!("Error: {}", error);
+        code = exit_code(ErrorKind::of(&error));
+    }
+    if let Err(error) = doc_builder.fetcher().save_cookies() {
+        eprintln!("Error: {}", error);
+        if code == 0 {
+            code = EXIT_ERROR;
+        }
+    }
+    let errors = doc_builder.errors();
+    if !errors.is_empty() {
+        eprintln!("{} error(s) in the render:", errors.len());
+        for (_, error) in &errors {
+            eprintln!("- {}", error);
+        }
+        if code == 0 {
+            code = exit_code(errors[0].0);
+        }
+    }
+    code
+}
+
+/// The logger of `--trace`, which writes the records to the stderr so that they never end up in the output.
+struct TraceLogger;
+
+impl log::Log for TraceLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn with_provenance<W: Write>(output: WriteOutput<W>, provenance: Option<BufWriter<File>>) -> WriteOutput<W> {
+    match provenance {
+        Some(provenance) => output.with_provenance(provenance),
+        None => output
+    }
+}
+
+/// Reads the template of the file, or returns the exit code if it is not read.
+fn read_template(path: &std::path::Path) -> Result<String, i32> {
+    std::fs::read_to_string(path).map_err(|error| {
+        eprintln!("Error: {}: {}", path.display(), error);
+        EXIT_ERROR
+    })
+}
+
+/// Fetches the page of the url, or reads it from the file.
+fn read_page(source: &str, doc_builder: &DocBuilder) -> Result<String, CommonError> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        doc_builder.fetch_doc(source, &RequestOptions{format: Some(DocFormat::Html), ..RequestOptions::default()})
+    } else {
+        std::fs::read_to_string(source).map_err(|e| format!("{}: {}", source, e).into())
+    }
 }
 
 #[cfg(test)]
@@ -56,4 +287,13 @@ mod tests {
         let doc_builder = DocBuilder::from(mock_data);
         assert_eq!(template_parser::parse("Hello {{css selector='div.name' doc='https://mock'}}!", &doc_builder).unwrap(), "Hello John!");
     }
+
+    #[test]
+    fn test_template_error() {
+        let doc_builder = DocBuilder::from(HashMap::new());
+        let error = template_parser::parse("{{css doc='https://mock'}}", &doc_builder).unwrap_err();
+        assert_eq!(error.to_string(), "[ERROR] unable to find `selector` in the css token: css doc='https://mock'");
+        assert_eq!(exit_code(ErrorKind::of(&error)), EXIT_TEMPLATE);
+        assert_eq!(template_parser::parse("Hello", &doc_builder).unwrap(), "Hello");
+    }
 }
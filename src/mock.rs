@@ -46,6 +46,9 @@ impl Document for MockCssDocument {
             None => Ok(None)
         }
     }
+    fn select_each_prop(&self, selector: &str, prop: &str) -> Result<Vec<Option<String>>, CommonError> {
+        Ok(self.map.get(selector).map(|v| v.iter().map(|item| item.prop(prop)).collect()).unwrap_or_default())
+    }
 }
 impl MockCssDocument {
     /*pub fn set(&mut self, selector: &'static str, value: &'static str) {
@@ -62,6 +65,7 @@ impl MockCssDocument {
         }
         self.map.get_mut(selector).unwrap()[0].prop_map.insert(prop_name, prop_value);
     }*/
+    #[allow(dead_code)]
     pub fn new() -> Self {
         MockCssDocument{map: HashMap::new()}
     }
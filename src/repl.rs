@@ -0,0 +1,122 @@
+use crate::document::css::CssDocument;
+use crate::template_parser::builder::DocBuilder;
+use crate::template_parser::token::{scan, Piece};
+use crate::template_parser::{self, CommonError};
+use std::io::{BufRead, Write};
+
+/// How many matches of a selector are shown.
+const MAX_SHOWN_MATCHES: usize = 20;
+/// How many characters of the text of a match are shown.
+const MAX_SHOWN_TEXT: usize = 80;
+/// The tokens which fetch a document, and take the document of the REPL if they have none.
+const DOC_KEYWORDS: &[&str] = &["css", "regex", "meta", "jsonld", "loop", "table"];
+
+/// Reads the lines of the input until `:q` or its end. A line with `{{` is rendered as a template
+/// snippet, and any other line is taken as a CSS selector whose matches are shown. The document is
+/// fetched once before, and is used by the tokens of the snippets without `doc` or `doc-var`.
+pub fn run(source: &str, text: &str, doc_builder: &DocBuilder, input: impl BufRead, output: &mut impl Write) -> Result<(), CommonError> {
+    let doc = CssDocument::from(text);
+    writeln!(output, "{}: {} bytes. Type a CSS selector or a template snippet, :q to quit.", source, text.len())?;
+    write!(output, "> ")?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        match line {
+            ":q" | ":quit" => return Ok(()),
+            "" => {},
+            _ if line.contains("{{") => {
+                let snippet = with_doc(line, source);
+                match template_parser::parse(&snippet, doc_builder) {
+                    Ok(rendered) => writeln!(output, "{}", rendered)?,
+                    Err(error) => writeln!(output, "Error: {}", error)?
+                }
+            },
+            _ => match doc.matches(line) {
+                Ok(matches) => {
+                    writeln!(output, "{} match(es)", matches.len())?;
+                    for (idx, node) in matches.iter().take(MAX_SHOWN_MATCHES).enumerate() {
+                        let attributes: String = node.attributes.iter().map(|(name, value)| format!(" {}={:?}", name, value)).collect();
+                        writeln!(output, "[{}] <{}{}> {}", idx, node.name, attributes, truncate(&node.text))?;
+                    }
+                    if matches.len() > MAX_SHOWN_MATCHES {
+                        writeln!(output, "... {} more", matches.len() - MAX_SHOWN_MATCHES)?;
+                    }
+                },
+                Err(error) => writeln!(output, "Error: {}", error)?
+            }
+        }
+        write!(output, "> ")?;
+        output.flush()?;
+    }
+    writeln!(output)?;
+    Ok(())
+}
+
+/// Adds `doc` of the source to the tokens fetching a document without `doc` or `doc-var`.
+fn with_doc(snippet: &str, source: &str) -> String {
+    let quote = if source.contains('\'') { '"' } else { '\'' };
+    let mut result = String::new();
+    let mut cursor = 0;
+    for piece in scan(snippet) {
+        if let Piece::Token(range, tokens) = piece {
+            let takes_doc = tokens.first().map(|x| DOC_KEYWORDS.contains(&x.as_str())).unwrap_or(false)
+                && !tokens.windows(2).any(|pair| (pair[0] == "doc" || pair[0] == "doc-var") && pair[1] == "=");
+            if takes_doc {
+                result.push_str(&snippet[cursor..range.end - 2]);
+                result.push_str(&format!(" doc={}{}{}", quote, source, quote));
+                cursor = range.end - 2;
+            }
+        }
+    }
+    result.push_str(&snippet[cursor..]);
+    result
+}
+
+fn truncate(text: &str) -> String {
+    match text.char_indices().nth(MAX_SHOWN_TEXT) {
+        Some((idx, _)) => format!("{}...", &text[..idx]),
+        None => String::from(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, with_doc};
+    use crate::template_parser::builder::DocBuilder;
+
+    const PAGE: &str = "<ul><li><a class='name' href='/alberta'>Alberta</a></li><li><a class='name' href='/quebec'>Quebec</a></li></ul>";
+
+    fn repl(input: &str) -> String {
+        let doc_builder = DocBuilder::from_text(PAGE).with_document("page.html", PAGE);
+        let mut output = Vec::new();
+        run("page.html", PAGE, &doc_builder, input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_with_doc() {
+        assert_eq!(with_doc("{{loop selector='li' var='p'}}{{var p}},{{end}}", "https://mock"), "{{loop selector='li' var='p' doc='https://mock'}}{{var p}},{{end}}");
+        assert_eq!(with_doc("{{css selector='h1' doc='https://other'}}", "https://mock"), "{{css selector='h1' doc='https://other'}}");
+        // Only the keys are the doc of the token, not the values.
+        assert_eq!(with_doc("{{css selector='doc'}}", "https://mock"), "{{css selector='doc' doc='https://mock'}}");
+        assert_eq!(with_doc("{{css selector='h1' doc-var='doc'}}", "https://mock"), "{{css selector='h1' doc-var='doc'}}");
+    }
+
+    #[test]
+    fn test_repl() {
+        assert_eq!(repl("a.name\nli:nth-child(2) a\n:q\nh1\n"), "page.html: 111 bytes. Type a CSS selector or a template snippet, :q to quit.
+> 2 match(es)
+[0] <a class=\"name\" href=\"/alberta\"> Alberta
+[1] <a class=\"name\" href=\"/quebec\"> Quebec
+> 1 match(es)
+[0] <a class=\"name\" href=\"/quebec\"> Quebec
+> ");
+        let output = repl("a[\nQuebec: {{regex pattern='/q[a-z]+'}}\n{{css}}");
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[1].starts_with("> Error: [ERROR] [CSS Parse Error]"));
+        assert_eq!(lines[2], "> Quebec: /quebec");
+        assert_eq!(lines[3], "> Error: [ERROR] unable to find `selector` in the css token: css doc='page.html'");
+        assert_eq!(lines[4], "> ");
+    }
+}
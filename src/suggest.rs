@@ -0,0 +1,45 @@
+use crate::document::css::CssDocument;
+use crate::template_parser::CommonError;
+
+/// How many texts of the matches are shown.
+const MAX_SHOWN_MATCHES: usize = 10;
+
+/// Suggests the selector of the nodes like the examples in the page, and returns it with the
+/// texts it matches and a `loop` over them, e.g.
+/// ```text
+/// div.field-item > ul > li > a
+/// 13 match(es): Alberta, British Columbia, Manitoba, ...
+/// {{loop selector='div.field-item > ul > li > a' var='item' doc='https://www.statcan.gc.ca/en/reference/province'}}
+/// - {{var item}}
+/// {{end}}
+/// ```
+pub fn suggest(source: &str, text: &str, examples: &[String]) -> Result<String, CommonError> {
+    let doc = CssDocument::from(text);
+    let selector = doc.suggest_selector(examples)?;
+    let matches = doc.matches(&selector)?;
+    let mut texts: Vec<&str> = matches.iter().take(MAX_SHOWN_MATCHES).map(|x| x.text.as_str()).collect();
+    if matches.len() > MAX_SHOWN_MATCHES {
+        texts.push("...");
+    }
+    let quote = if source.contains('\'') { '"' } else { '\'' };
+    Ok(format!("{}\n{} match(es): {}\n{{{{loop selector='{}' var='item' doc={}{}{}}}}}\n- {{{{var item}}}}\n{{{{end}}}}\n",
+        selector, matches.len(), texts.join(", "), selector, quote, source, quote))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::suggest;
+
+    #[test]
+    fn test_suggest() {
+        let page = "<div class='field-item even'><ul><li><a href='/ab'>Alberta</a></li><li><a href='/qc'>Quebec</a></li></ul></div><ul><li><a href='/'>Home</a></li></ul>";
+        let examples = vec![String::from("Alberta"), String::from("Quebec")];
+        assert_eq!(suggest("https://mock", page, &examples).unwrap(), "div.even.field-item > ul > li > a
+2 match(es): Alberta, Quebec
+{{loop selector='div.even.field-item > ul > li > a' var='item' doc='https://mock'}}
+- {{var item}}
+{{end}}
+");
+        assert!(suggest("https://mock", page, &[String::from("Ontario")]).is_err());
+    }
+}
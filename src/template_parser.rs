@@ -1,36 +1,221 @@
-mod token;
+pub mod ast;
 pub mod builder;
+pub mod check;
+pub mod output;
+pub mod pool;
+pub mod sandbox;
+pub mod token;
 
 use token::TokenParser;
 use crate::document::{Document,ParseError};
-use std::collections::HashMap;
+use crate::document::json_path;
+use crate::fetch::{FetchError, RequestOptions};
+use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::ops::Range;
+use std::time::{Instant, SystemTime};
+use ast::AstNode;
 use builder::DocBuilder;
+use output::{Buffer, Output, Provenance};
+use regex::Regex;
+use sandbox::SandboxError;
 
 pub type Template = Vec<Box<dyn TemplateNode>>;
-pub type CommonError = Box<dyn std::error::Error>;
+pub type CommonError = Box<dyn std::error::Error + Send + Sync>;
 
 #[allow(dead_code)]
 pub fn parse(template: &str, doc_builder: &DocBuilder) -> Result<String, CommonError> {
-    let template_nodes = parse_template(template, &doc_builder)?;
     let mut buff = String::new();
+    render(template, doc_builder, &mut buff)?;
+    Ok(buff)
+}
+
+/// Renders the template into the output, which receives each node as soon as it is evaluated.
+pub fn render(template: &str, doc_builder: &DocBuilder, out: &mut dyn Output) -> Result<(), CommonError> {
+    let template_nodes = parse_template(template, doc_builder)?;
+    doc_builder.sandbox().start();
+    doc_builder.prefetch(&plan_template(&template_nodes).static_docs);
     for node in &template_nodes {
-        node.evaluate(None, &mut buff)?;
+        if let Err(error) = node.evaluate(None, out) {
+            if let Some(placeholder) = doc_builder.recover(doc_builder.on_error(), error)? {
+                out.write_str(placeholder)?;
+            }
+        }
+        doc_builder.sandbox().check_output(out.written())?;
     }
-    Ok(buff)
+    out.flush()
+}
+
+/// Plans the documents that the template requests, without fetching them.
+pub fn plan(template: &str, doc_builder: &DocBuilder) -> Result<Plan, CommonError> {
+    Ok(plan_template(&parse_template(template, doc_builder)?))
+}
+
+/// Parses the template into the tree of its nodes, e.g. to see which `{{end}}` closes which loop.
+pub fn ast(template: &str, doc_builder: &DocBuilder) -> Result<Vec<AstNode>, CommonError> {
+    Ok(parse_template(template, doc_builder)?.iter().map(|node| node.ast()).collect())
+}
+
+fn plan_template(template_nodes: &Template) -> Plan {
+    let mut plan = Plan::default();
+    for node in template_nodes {
+        node.plan(&mut plan);
+    }
+    plan
 }
 
+/// The documents that a template requests, as far as they are known before the render.
+#[derive(Debug,Default,PartialEq)]
+pub struct Plan {
+    /// The urls of `doc`(with `base-doc`), which are known when the template is parsed.
+    pub static_docs: Vec<(String, RequestOptions)>,
+    /// The urls of `doc-var`, which are only known when the template is rendered, e.g. `GET {doc-var 'url'}`.
+    pub dynamic_docs: Vec<String>
+}
+
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (url, request) in &self.static_docs {
+            writeln!(f, "{} {}", request.method(), url)?;
+        }
+        for doc in &self.dynamic_docs {
+            writeln!(f, "{}", doc)?;
+        }
+        Ok(())
+    }
+}
+
+/// What to do when a token fails to render, e.g. when its document fails to be fetched.
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub enum OnError {
+    /// Fails the whole render.
+    #[default]
+    Abort,
+    /// Renders nothing for the failing token or loop iteration.
+    Skip,
+    /// Renders the placeholder `[ERROR]` for the failing token or loop iteration.
+    Placeholder
+}
+
+impl std::str::FromStr for OnError {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "abort" => Ok(OnError::Abort),
+            "skip" => Ok(OnError::Skip),
+            "placeholder" => Ok(OnError::Placeholder),
+            _ => Err(format!("unknown 'on-error': {}, expect 'abort', 'skip' or 'placeholder'", value))
+        }
+    }
+}
+
+impl std::fmt::Display for OnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OnError::Abort => write!(f, "abort"),
+            OnError::Skip => write!(f, "skip"),
+            OnError::Placeholder => write!(f, "placeholder")
+        }
+    }
+}
+
+/// The text rendered in place of a failing token or loop iteration by `on-error='placeholder'`.
+pub const ERROR_PLACEHOLDER: &str = "[ERROR]";
+
+/// An error in the syntax of the template, e.g. an `{{end}}` without its `loop`.
+#[derive(Debug,Clone)]
+pub struct TemplateError {
+    error: String
+}
+
+impl std::error::Error for TemplateError {}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[ERROR] {}", self.error)
+    }
+}
+
+impl TemplateError {
+    pub fn new(error: String) -> Self {
+        TemplateError{error}
+    }
+
+    pub fn message(&self) -> &str {
+        &self.error
+    }
+}
+
+/// A value that the template requires but the document does not have, e.g. a `css` token that
+/// selects nothing and has no 'default', or a `loop` of 'required' that selects nothing.
+#[derive(Debug,Clone)]
+pub struct MissingValueError {
+    error: String
+}
+
+impl std::error::Error for MissingValueError {}
+
+impl std::fmt::Display for MissingValueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[ERROR] {}", self.error)
+    }
+}
+
+impl MissingValueError {
+    pub fn new(error: String) -> Self {
+        MissingValueError{error}
+    }
+}
+
+/// The kind of an error of the render, which decides the exit code of the command.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ErrorKind {
+    Template,
+    Fetch,
+    MissingValue,
+    Sandbox,
+    Other
+}
+
+impl ErrorKind {
+    pub fn of(error: &CommonError) -> Self {
+        // An invalid selector, pattern or path of a token fails to parse when it is evaluated.
+        if error.is::<TemplateError>() || error.is::<ParseError>() {
+            ErrorKind::Template
+        } else if error.is::<FetchError>() {
+            ErrorKind::Fetch
+        } else if error.is::<MissingValueError>() {
+            ErrorKind::MissingValue
+        } else if error.is::<SandboxError>() {
+            ErrorKind::Sandbox
+        } else {
+            ErrorKind::Other
+        }
+    }
+}
 
 fn parse_template(template: &str, doc_builder: &DocBuilder) -> Result<Template, CommonError> {
     let mut temp: Template = Template::new();    
-    let mut token_parser = TokenParser::new(template, doc_builder);
+    let token_parser = TokenParser::new(template, doc_builder);
     let mut last_containers: Vec<Box<dyn TemplateNode>> = Vec::new();
-    while let Some(token) = token_parser.next() {
+    for token in token_parser {
+        let token = token?;
         if token.is_container() {
             last_containers.push(token);
+            doc_builder.sandbox().check_nesting_depth(last_containers.len())?;
+            continue;
+        }
+        if token.is_else() {
+            match last_containers.last_mut() {
+                Some(container) => container.start_else()?,
+                None => return Err(Box::new(TemplateError::new(String::from("the 'else' token must be inside a 'loop' token"))))
+            }
             continue;
         }
         if token.is_end() {
-            let container_token = last_containers.pop().expect("The end token can not match the exist 'if' or 'loop' token");
+            let mut container_token = last_containers.pop().ok_or_else(|| TemplateError::new(String::from("the 'end' token does not match any 'loop' or 'table' token")))?;
+            container_token.close(token.ast().span.end);
             if last_containers.is_empty() {
                 temp.push(container_token);
             } else {
@@ -45,161 +230,855 @@ fn parse_template(template: &str, doc_builder: &DocBuilder) -> Result<Template,
     Ok(temp)
 }
 
-pub trait TemplateNode: std::fmt::Debug {
-    fn evaluate(&self, context: Option<HashMap<String,String>>, buff: &mut String) -> Result<(), CommonError>;
+pub trait TemplateNode: std::fmt::Debug + Send + Sync {
+    fn evaluate(&self, context: Option<HashMap<String,String>>, out: &mut dyn Output) -> Result<(), CommonError>;
+    /// Whether the node, or one of its children, fetches a document when it is evaluated.
+    fn fetches_doc(&self) -> bool {false}
+    /// Adds the documents requested by the node and its children to the plan.
+    fn plan(&self, _plan: &mut Plan) {}
     fn is_container(&self) -> bool {false}
     fn is_end(&self) -> bool {false}
+    fn is_else(&self) -> bool {false}
     fn add_child(&mut self, _node: Box<dyn TemplateNode>) -> Result<(), CommonError> {Err(Box::new(ParseError::new("not implemented yet")))}
+    fn start_else(&mut self) -> Result<(), CommonError> {Err(Box::new(TemplateError::new(String::from("the 'else' token is not supported by the container"))))}
+    /// Closes the container by the `end` token, which ends at the byte position.
+    fn close(&mut self, _end: usize) {}
+    /// The node and its children with their positions in the template.
+    fn ast(&self) -> AstNode;
 }
 
 #[derive(Debug)]
 struct StrTemplateNode {
-    text: String
+    text: String,
+    span: Range<usize>
 }
 
 impl TemplateNode for StrTemplateNode {
-    fn evaluate(&self, _context: Option<HashMap<String,String>>, buff: &mut String) -> Result<(), CommonError> {
-        buff.push_str(&self.text);
-        Ok(())
+    fn evaluate(&self, _context: Option<HashMap<String,String>>, out: &mut dyn Output) -> Result<(), CommonError> {
+        out.write_str(&self.text)
     }
+
+    fn ast(&self) -> AstNode {
+        let mut node = AstNode::new("text", self.span.clone());
+        node.attribute("text", Some(&self.text));
+        node
+    }
+}
+
+/// A token with its own `on-error`, which renders the token aside so that nothing of a failing
+/// token is rendered.
+#[derive(Debug)]
+struct OnErrorTemplateNode {
+    on_error: OnError,
+    node: Box<dyn TemplateNode>,
+    doc_builder: DocBuilder
 }
 
-fn get_doc(context: &Option<HashMap<String,String>>, doc: &Option<String>, doc_var: &Option<String>, base_doc: &Option<String>) -> String {
-    if doc.is_none() && doc_var.is_none() {
-        panic!("'doc' or 'doc-var' must be used for the template token");
+impl TemplateNode for OnErrorTemplateNode {
+    fn evaluate(&self, context: Option<HashMap<String,String>>, out: &mut dyn Output) -> Result<(), CommonError> {
+        let mut buff = Buffer::default();
+        match self.node.evaluate(context, &mut buff) {
+            Ok(()) => buff.write_to(out),
+            Err(error) => match self.doc_builder.recover(self.on_error, error)? {
+                Some(placeholder) => out.write_str(placeholder),
+                None => Ok(())
+            }
+        }
+    }
+
+    fn fetches_doc(&self) -> bool {
+        self.node.fetches_doc()
     }
 
-    let mut doc_url = if let Some(doc_var_url) = doc_var {
-        let context = context.clone().expect("empty context for the 'doc-var'");
-        if let Some(context_doc_url) = context.get(doc_var_url){
-            context_doc_url.clone()
-        } else {
-            doc.clone().expect("'doc-var' does not exist in the context")
+    fn plan(&self, plan: &mut Plan) {
+        self.node.plan(plan);
+    }
+
+    fn ast(&self) -> AstNode {
+        let mut node = self.node.ast();
+        node.attribute("on-error", Some(self.on_error));
+        node
+    }
+}
+
+/// Where a token takes its document from, and how to request it.
+#[derive(Debug,Clone,Default)]
+struct DocSource {
+    doc: Option<String>,
+    doc_var: Option<String>,
+    base_doc: Option<String>,
+    request: RequestOptions
+}
+
+impl DocSource {
+    fn ast(&self, node: &mut AstNode) {
+        node.attribute("doc", self.doc.as_ref());
+        node.attribute("doc-var", self.doc_var.as_ref());
+        node.attribute("base-doc", self.base_doc.as_ref());
+        node.attribute("method", self.request.method.as_ref());
+        for (name, value) in &self.request.headers {
+            node.attribute(&format!("header-{}", name), Some(value));
         }
-    } else {
-        doc.clone().expect("'doc' does not exist")
-    };
+        for (name, value) in &self.request.form {
+            node.attribute(&format!("form-{}", name), Some(value));
+        }
+        node.attribute("json-body", self.request.json_body.as_ref());
+        node.attribute("timeout", self.request.timeout.map(|x| x.as_secs_f64()));
+        node.attribute("retries", self.request.retries);
+        node.attribute("retry-delay", self.request.retry_delay.map(|x| x.as_secs_f64()));
+        node.attribute("encoding", self.request.encoding.as_ref());
+    }
 
-    if !doc_url.starts_with("http") && base_doc.is_some() {
-        doc_url = base_doc.clone().unwrap() + &doc_url;
+    fn plan(&self, plan: &mut Plan) {
+        if let Some(doc_var) = &self.doc_var {
+            let mut doc = format!("{} {{doc-var '{}'}}", self.request.method(), doc_var);
+            if let Some(base_doc) = &self.base_doc {
+                doc.push_str(&format!(" with base-doc {}", base_doc));
+            }
+            if !plan.dynamic_docs.contains(&doc) {
+                plan.dynamic_docs.push(doc);
+            }
+        } else if let Ok(doc_url) = resolve_doc_url(&None, self) {
+            let doc = (doc_url, self.request.clone());
+            if !plan.static_docs.contains(&doc) {
+                plan.static_docs.push(doc);
+            }
+        }
+    }
+}
+
+fn get_doc(context: &Option<HashMap<String,String>>, source: &DocSource, doc_builder: &DocBuilder) -> Result<String, CommonError> {
+    let doc_url = resolve_doc_url(context, source)?;
+    let started = Instant::now();
+    let result = doc_builder.fetch_doc(&doc_url, &source.request);
+    match &result {
+        Ok(text) => log::trace!("document {}: {} bytes in {:?}", doc_url, text.len(), started.elapsed()),
+        Err(error) => log::trace!("document {}: failed in {:?}: {}", doc_url, started.elapsed(), error)
     }
+    result
+}
+
+/// The url of the document of the token, or an error if its `doc-var` is not in the context and
+/// it has no `doc` either.
+fn resolve_doc_url(context: &Option<HashMap<String,String>>, source: &DocSource) -> Result<String, CommonError> {
+    let DocSource{doc, doc_var, base_doc, ..} = source;
+
+    // The url of a `doc-var` is relative to the document it was selected from, unless `base-doc` is given.
+    let context_doc_url = doc_var.as_ref().and_then(|doc_var_url| context.as_ref()
+        .and_then(|context| context.get(doc_var_url).map(|url| (url.clone(), context.get(&doc_url_key(doc_var_url)).cloned()))));
+    let (doc_url, value_doc_url) = match (context_doc_url, doc, doc_var) {
+        (Some(context_doc_url), _, _) => context_doc_url,
+        (None, Some(doc), _) => (doc.clone(), None),
+        (None, None, Some(doc_var_url)) => return Err(Box::new(TemplateError::new(format!("the 'doc-var' '{}' does not exist in the context", doc_var_url)))),
+        (None, None, None) => return Err(Box::new(TemplateError::new(String::from("'doc' or 'doc-var' must be used for the template token"))))
+    };
+
+    Ok(match base_doc.clone().or(value_doc_url) {
+        Some(base) => resolve_url(&base, &doc_url),
+        None => doc_url
+    })
+}
+
+/// The variables of the position of each iteration of a loop, besides the variable of the loop.
+const LOOP_VARIABLES: [&str; 5] = ["loop.index", "loop.index0", "loop.first", "loop.last", "loop.length"];
 
-    #[cfg(not(test))]
-    {
-        reqwest::blocking::get(&doc_url).expect(&format!("failed to access the document at: {}", doc_url)).text().expect(&format!("failed to get the text of the document: {}", doc_url))
+/// The context key of the url of the document that the value of the variable was selected from.
+fn doc_url_key(var_name: &str) -> String {
+    format!("{}@doc", var_name)
+}
+
+/// The key of the selector that the value of the variable was selected by, for the provenance.
+fn selector_key(var_name: &str) -> String {
+    format!("{}@selector", var_name)
+}
+
+/// The key of the index of the node that the value of the variable was selected from, for the provenance.
+fn node_index_key(var_name: &str) -> String {
+    format!("{}@index", var_name)
+}
+
+/// The value that a token emits, or its default, for the trace.
+fn emitted(selected_value: Option<&String>, default_value: Option<&String>) -> String {
+    selected_value.or(default_value).map(|x| format!("{:?}", x)).unwrap_or_else(|| String::from("nothing"))
+}
+
+/// Resolves the url against the base url per RFC 3986, e.g. `../page`, `/page`, `?page=2` and
+/// `//cdn.example.com/page`. An absolute url, or a url with a base that is not an absolute url, is
+/// kept as it is.
+fn resolve_url(base: &str, url: &str) -> String {
+    let url = url.trim();
+    if url::Url::parse(url).is_ok() {
+        return String::from(url);
     }
-    #[cfg(test)] {
-        String::new()
+    match url::Url::parse(base.trim()).and_then(|base| base.join(url)) {
+        Ok(resolved) => resolved.into(),
+        Err(_) => String::from(url)
     }
 }
 
 #[derive(Debug)]
 struct CssTemplateNode {
+    span: Range<usize>,
     css_selector: String,
     default_value: Option<String>,
     node_property: Option<String>,
-    doc: Option<String>,
-    doc_var: Option<String>,
-    base_doc: Option<String>,
+    /// Whether to resolve the selected value as a url against the url of the document.
+    absolute_url: bool,
+    source: DocSource,
     doc_builder: DocBuilder
 }
 
 impl TemplateNode for CssTemplateNode {
-    fn evaluate(&self, context: Option<HashMap<String,String>>, buff: &mut String) -> Result<(), CommonError> {
-        let html_text = get_doc(&context, &self.doc, &self.doc_var, &self.base_doc);
-        let css_doc = self.doc_builder.build_doc(&*html_text);
+    fn evaluate(&self, context: Option<HashMap<String,String>>, out: &mut dyn Output) -> Result<(), CommonError> {
+        let doc_url = resolve_doc_url(&context, &self.source)?;
+        let html_text = get_doc(&context, &self.source, &self.doc_builder)?;
+        let css_doc = self.doc_builder.build_doc(&html_text);
+
+        // The token renders the first match, so all of them are only selected to count them for the trace.
+        let (mut selected_value, matches) = if log::log_enabled!(log::Level::Trace) {
+            let selected = match &self.node_property {
+                Some(property) => css_doc.select_each_prop(&self.css_selector, property)?,
+                None => css_doc.select_all(&self.css_selector)?.unwrap_or_default().into_iter().map(Some).collect()
+            };
+            (selected.first().cloned().flatten(), selected.len())
+        } else {
+            let selected = match &self.node_property {
+                Some(property) => css_doc.select_prop(&self.css_selector, property),
+                None => css_doc.select(&self.css_selector)
+            }?;
+            (selected, 0)
+        };
+        if self.absolute_url {
+            selected_value = selected_value.map(|value| resolve_url(&doc_url, &value));
+        }
+        log::trace!("css '{}' at {}: {} match(es), emits {}", self.css_selector, doc_url, matches, emitted(selected_value.as_ref(), self.default_value.as_ref()));
 
-        let selected_value = match &self.node_property {
-            Some(property) => css_doc.select_prop(&self.css_selector, &property),
-            None => css_doc.select(&self.css_selector)
+        let start = out.written();
+        let node_index = match &selected_value {
+            Some(value) => {
+                out.write_str(value)?;
+                Some(0)
+            },
+            None => match &self.default_value {
+                Some(default) => {
+                    out.write_str(default)?;
+                    None
+                },
+                None => return Err(Box::new(MissingValueError::new(String::from("Not able to render the variable")))),
+            }
+        };
+        out.record(Provenance{
+            output: start..out.written(),
+            template: self.span.clone(),
+            url: Some(doc_url),
+            selector: Some(self.css_selector.clone()),
+            node_index,
+            timestamp: SystemTime::now()
+        })
+    }
+
+    fn fetches_doc(&self) -> bool {
+        true
+    }
+
+    fn plan(&self, plan: &mut Plan) {
+        self.source.plan(plan);
+    }
+
+    fn ast(&self) -> AstNode {
+        let mut node = AstNode::new("css", self.span.clone());
+        node.attribute("selector", Some(&self.css_selector));
+        node.attribute("node-property", self.node_property.as_ref());
+        node.attribute("default", self.default_value.as_ref());
+        node.attribute("absolute-url", Some(self.absolute_url).filter(|x| *x));
+        self.source.ast(&mut node);
+        node
+    }
+}
+
+#[derive(Debug)]
+struct RegexTemplateNode {
+    span: Range<usize>,
+    pattern: String,
+    group: Option<String>,
+    default_value: Option<String>,
+    source: DocSource,
+    doc_builder: DocBuilder
+}
+
+impl TemplateNode for RegexTemplateNode {
+    fn evaluate(&self, context: Option<HashMap<String,String>>, out: &mut dyn Output) -> Result<(), CommonError> {
+        let doc_url = resolve_doc_url(&context, &self.source)?;
+        let text = get_doc(&context, &self.source, &self.doc_builder)?;
+        let regex_doc = self.doc_builder.build_regex_doc(&text);
+
+        let selected_value = match &self.group {
+            Some(group) => regex_doc.select_prop(&self.pattern, group),
+            None => regex_doc.select(&self.pattern)
         }?;
+        log::trace!("regex '{}' at {}: emits {}", self.pattern, doc_url, emitted(selected_value.as_ref(), self.default_value.as_ref()));
 
         match selected_value {
-            Some(value) => buff.push_str(&value),
+            Some(value) => out.write_str(&value)?,
             None => match &self.default_value {
-                Some(default) => buff.push_str(&default),
-                None => return Err(Box::new(ParseError::new("Not able to render the variable"))),
+                Some(default) => out.write_str(default)?,
+                None => return Err(Box::new(MissingValueError::new(format!("no match for the pattern: {}", self.pattern)))),
             }
         }
         Ok(())
     }
+
+    fn fetches_doc(&self) -> bool {
+        true
+    }
+
+    fn plan(&self, plan: &mut Plan) {
+        self.source.plan(plan);
+    }
+
+    fn ast(&self) -> AstNode {
+        let mut node = AstNode::new("regex", self.span.clone());
+        node.attribute("pattern", Some(&self.pattern));
+        node.attribute("group", self.group.as_ref());
+        node.attribute("default", self.default_value.as_ref());
+        self.source.ast(&mut node);
+        node
+    }
+}
+
+#[derive(Debug)]
+struct MetaTemplateNode {
+    span: Range<usize>,
+    name: String,
+    item_type: Option<String>,
+    default_value: Option<String>,
+    source: DocSource,
+    doc_builder: DocBuilder
+}
+
+impl TemplateNode for MetaTemplateNode {
+    fn evaluate(&self, context: Option<HashMap<String,String>>, out: &mut dyn Output) -> Result<(), CommonError> {
+        let doc_url = resolve_doc_url(&context, &self.source)?;
+        let html_text = get_doc(&context, &self.source, &self.doc_builder)?;
+        let html_doc = self.doc_builder.build_html_doc(&html_text);
+
+        let selected_value = html_doc.meta(&self.name, self.item_type.as_deref())?;
+        log::trace!("meta '{}' at {}: emits {}", self.name, doc_url, emitted(selected_value.as_ref(), self.default_value.as_ref()));
+        match selected_value {
+            Some(value) => out.write_str(&value)?,
+            None => match &self.default_value {
+                Some(default) => out.write_str(default)?,
+                None => return Err(Box::new(MissingValueError::new(format!("no meta data is named: {}", self.name)))),
+            }
+        }
+        Ok(())
+    }
+
+    fn fetches_doc(&self) -> bool {
+        true
+    }
+
+    fn plan(&self, plan: &mut Plan) {
+        self.source.plan(plan);
+    }
+
+    fn ast(&self) -> AstNode {
+        let mut node = AstNode::new("meta", self.span.clone());
+        node.attribute("name", Some(&self.name));
+        node.attribute("type", self.item_type.as_ref());
+        node.attribute("default", self.default_value.as_ref());
+        self.source.ast(&mut node);
+        node
+    }
+}
+
+#[derive(Debug)]
+struct JsonLdTemplateNode {
+    span: Range<usize>,
+    path: String,
+    item_type: Option<String>,
+    default_value: Option<String>,
+    source: DocSource,
+    doc_builder: DocBuilder
+}
+
+impl TemplateNode for JsonLdTemplateNode {
+    fn evaluate(&self, context: Option<HashMap<String,String>>, out: &mut dyn Output) -> Result<(), CommonError> {
+        let doc_url = resolve_doc_url(&context, &self.source)?;
+        let html_text = get_doc(&context, &self.source, &self.doc_builder)?;
+        let html_doc = self.doc_builder.build_html_doc(&html_text);
+
+        let items = html_doc.json_ld(self.item_type.as_deref())?;
+        let mut selected_value = None;
+        for item in &items {
+            if let Some(value) = json_path::select(item, &self.path)?.first() {
+                selected_value = Some(json_path::to_text(value));
+                break;
+            }
+        }
+        log::trace!("jsonld '{}' at {}: {} item(s), emits {}", self.path, doc_url, items.len(), emitted(selected_value.as_ref(), self.default_value.as_ref()));
+
+        match selected_value {
+            Some(value) => out.write_str(&value)?,
+            None => match &self.default_value {
+                Some(default) => out.write_str(default)?,
+                None => return Err(Box::new(MissingValueError::new(format!("no JSON-LD value is found by the path: {}", self.path)))),
+            }
+        }
+        Ok(())
+    }
+
+    fn fetches_doc(&self) -> bool {
+        true
+    }
+
+    fn plan(&self, plan: &mut Plan) {
+        self.source.plan(plan);
+    }
+
+    fn ast(&self) -> AstNode {
+        let mut node = AstNode::new("jsonld", self.span.clone());
+        node.attribute("path", Some(&self.path));
+        node.attribute("type", self.item_type.as_ref());
+        node.attribute("default", self.default_value.as_ref());
+        self.source.ast(&mut node);
+        node
+    }
+}
+
+/// How the `selector` of a loop is interpreted.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum SelectorKind {
+    /// `selector='...'`, a css selector.
+    Css,
+    /// `pattern='...'`, a regular expression over the raw text of the document.
+    Regex,
+    /// `{{table selector='...'}}`, the rows of the selected HTML table.
+    Table
+}
+
+/// A value selected by a loop, with the variables bound along with it.
+#[derive(Debug)]
+struct LoopItem {
+    value: String,
+    /// The index of the node among the matches of the selector, before the values are refined.
+    index: usize,
+    bindings: HashMap<String,String>
 }
 
 #[derive(Debug)]
 struct LoopTemplateNode {
+    span: Range<usize>,
     var_name: String,
-    css_selector: String,
+    selector: String,
+    selector_kind: SelectorKind,
     node_property: Option<String>,
-    doc: Option<String>,
-    doc_var: Option<String>,
-    base_doc: Option<String>,
+    /// Whether to resolve the selected values as urls against the url of the document.
+    absolute_url: bool,
+    source: DocSource,
+    separator: Option<String>,
+    refinement: LoopRefinement,
+    required: bool,
+    /// What to do when the loop or one of its iterations fails, the `--on-error` by default.
+    on_error: Option<OnError>,
     children: Vec<Box<dyn TemplateNode>>,
+    else_children: Option<Vec<Box<dyn TemplateNode>>>,
     doc_builder: DocBuilder
 }
 
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum LoopOrder {
+    Asc,
+    Desc,
+    Natural
+}
+
+impl std::fmt::Display for LoopOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoopOrder::Asc => write!(f, "asc"),
+            LoopOrder::Desc => write!(f, "desc"),
+            LoopOrder::Natural => write!(f, "natural")
+        }
+    }
+}
+
+/// The attributes that narrow or reorder the values selected by a `loop` before it iterates.
+/// They are applied in the order: `where`, `unique`, `sort`, `reverse`, `offset`, `step`, `limit`.
+#[derive(Debug,Default)]
+struct LoopRefinement {
+    limit: Option<usize>,
+    offset: Option<usize>,
+    step: Option<usize>,
+    reverse: bool,
+    sort: Option<LoopOrder>,
+    unique: bool,
+    where_pattern: Option<Regex>
+}
+
+impl LoopRefinement {
+    /// Adds the attributes to the node in the order they are applied.
+    fn ast(&self, node: &mut AstNode) {
+        node.attribute("where", self.where_pattern.as_ref().map(|x| x.as_str()));
+        node.attribute("unique", Some(self.unique).filter(|x| *x));
+        node.attribute("sort", self.sort);
+        node.attribute("reverse", Some(self.reverse).filter(|x| *x));
+        node.attribute("offset", self.offset);
+        node.attribute("step", self.step);
+        node.attribute("limit", self.limit);
+    }
+
+    fn apply(&self, mut values: Vec<LoopItem>) -> Vec<LoopItem> {
+        if let Some(pattern) = &self.where_pattern {
+            values.retain(|item| pattern.is_match(&item.value));
+        }
+        if self.unique {
+            let mut seen = HashSet::new();
+            values.retain(|item| seen.insert(item.value.clone()));
+        }
+        match self.sort {
+            Some(LoopOrder::Asc) => values.sort_by(|a, b| a.value.cmp(&b.value)),
+            Some(LoopOrder::Desc) => values.sort_by(|a, b| b.value.cmp(&a.value)),
+            Some(LoopOrder::Natural) => values.sort_by(|a, b| natural_cmp(&a.value, &b.value)),
+            None => {}
+        }
+        if self.reverse {
+            values.reverse();
+        }
+        values.into_iter()
+            .skip(self.offset.unwrap_or(0))
+            .step_by(self.step.unwrap_or(1))
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+/// Compares two strings the way a human would, so that "item 2" comes before "item 10".
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    loop {
+        match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let mut a_num = String::new();
+                while let Some(c) = a_chars.next_if(|c| c.is_ascii_digit()) {
+                    a_num.push(c);
+                }
+                let mut b_num = String::new();
+                while let Some(c) = b_chars.next_if(|c| c.is_ascii_digit()) {
+                    b_num.push(c);
+                }
+                let a_trimmed = a_num.trim_start_matches('0');
+                let b_trimmed = b_num.trim_start_matches('0');
+                let ordering = a_trimmed.len().cmp(&b_trimmed.len()).then_with(|| a_trimmed.cmp(b_trimmed));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            },
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
 impl TemplateNode for LoopTemplateNode {
-    fn evaluate(&self, context: Option<HashMap<String,String>>, buff: &mut String) -> Result<(), CommonError> {
-        let html_text = get_doc(&context, &self.doc, &self.doc_var, &self.base_doc);
-        let css_doc = self.doc_builder.build_doc(&*html_text);
-
-        let selected_values = match &self.node_property {
-            Some(property) => css_doc.select_all_prop(&self.css_selector, &property),
-            None => css_doc.select_all(&self.css_selector)
-        }.expect(&format!("failed to render css selector: {}", self.css_selector));
-
-        if let Some(values) = selected_values {
-            for value in values {
-                let mut context = HashMap::new();
-                context.insert(self.var_name.clone(), value.clone());
-                for node in &self.children {
-                    node.evaluate(Some(context.clone()), buff)?  
+    fn evaluate(&self, context: Option<HashMap<String,String>>, out: &mut dyn Output) -> Result<(), CommonError> {
+        let doc_url = resolve_doc_url(&context, &self.source)?;
+        // The failure of the loop itself is left to the parent, unless the loop has its own `on-error`.
+        let values = match (self.select_values(&context, &doc_url), self.on_error) {
+            (Ok(values), _) => values,
+            (Err(error), Some(on_error)) => {
+                if let Some(placeholder) = self.doc_builder.recover(on_error, error)? {
+                    out.write_str(placeholder)?;
+                }
+                return Ok(());
+            },
+            (Err(error), None) => return Err(error)
+        };
+        if values.is_empty() {
+            if let Some(else_children) = &self.else_children {
+                for node in else_children {
+                    node.evaluate(context.clone(), out)?
+                }
+            }
+            return Ok(());
+        }
+
+        let length = values.len();
+        let contexts: Vec<HashMap<String,String>> = values.into_iter().enumerate().map(|(index, item)| {
+            let mut context = context.clone().unwrap_or_default();
+            for name in item.bindings.keys().chain(std::iter::once(&self.var_name)) {
+                context.insert(doc_url_key(name), doc_url.clone());
+                context.insert(selector_key(name), self.selector.clone());
+                context.insert(node_index_key(name), item.index.to_string());
+            }
+            context.extend(item.bindings);
+            context.insert(self.var_name.clone(), item.value);
+            let position = [(index + 1).to_string(), index.to_string(), (index == 0).to_string(), (index + 1 == length).to_string(), length.to_string()];
+            context.extend(LOOP_VARIABLES.iter().map(|name| String::from(*name)).zip(position));
+            context
+        }).collect();
+        let on_error = self.on_error.unwrap_or_else(|| self.doc_builder.on_error());
+        // How many iterations are rendered, which are separated by the separator. A skipped iteration is not counted.
+        let mut rendered_count = 0;
+        if self.children.iter().any(|node| node.fetches_doc()) {
+            // The iterations fetching documents are rendered concurrently into their own buffers,
+            // and each of them is written as soon as the earlier ones are written.
+            self.doc_builder.workers().for_each_ordered(contexts, |context| {
+                self.doc_builder.sandbox().check_time()?;
+                self.render_iteration(&context)
+            }, |iteration_buff| self.write_iteration(iteration_buff, on_error, &mut rendered_count, out))?;
+        } else {
+            for context in contexts {
+                self.doc_builder.sandbox().check_time()?;
+                if on_error == OnError::Abort {
+                    self.push_separator(rendered_count, out)?;
+                    for node in &self.children {
+                        node.evaluate(Some(context.clone()), out)?
+                    }
+                    rendered_count += 1;
+                    self.doc_builder.sandbox().check_output(out.written())?;
+                    out.flush()?;
+                } else {
+                    // The iteration is rendered aside, so that nothing of a failing iteration is rendered.
+                    self.write_iteration(self.render_iteration(&context), on_error, &mut rendered_count, out)?;
                 }
             }
         }
         Ok(()) 
     }
 
+    fn fetches_doc(&self) -> bool {
+        true
+    }
+
+    fn plan(&self, plan: &mut Plan) {
+        self.source.plan(plan);
+        for node in self.children.iter().chain(self.else_children.iter().flatten()) {
+            node.plan(plan);
+        }
+    }
+
     fn is_container(&self) -> bool {
         true
     }
 
     fn add_child(&mut self, node: Box<dyn TemplateNode>) -> Result<(), CommonError> {
-        self.children.push(node);
+        match &mut self.else_children {
+            Some(else_children) => else_children.push(node),
+            None => self.children.push(node)
+        }
+        Ok(())
+    }
+
+    fn start_else(&mut self) -> Result<(), CommonError> {
+        if self.else_children.is_some() {
+            return Err(Box::new(TemplateError::new(format!("duplicated 'else' token in the loop of the selector: {}", self.selector))));
+        }
+        self.else_children = Some(Vec::new());
         Ok(())
     }
+
+    fn close(&mut self, end: usize) {
+        self.span.end = end;
+    }
+
+    fn ast(&self) -> AstNode {
+        let (kind, selector_key) = match self.selector_kind {
+            SelectorKind::Css => ("loop", "selector"),
+            SelectorKind::Regex => ("loop", "pattern"),
+            SelectorKind::Table => ("table", "selector")
+        };
+        let mut node = AstNode::new(kind, self.span.clone());
+        node.attribute("var", Some(&self.var_name));
+        node.attribute(selector_key, Some(&self.selector));
+        node.attribute("node-property", self.node_property.as_ref());
+        node.attribute("separator", self.separator.as_ref());
+        self.refinement.ast(&mut node);
+        node.attribute("required", Some(self.required).filter(|x| *x));
+        node.attribute("absolute-url", Some(self.absolute_url).filter(|x| *x));
+        self.source.ast(&mut node);
+        node.attribute("on-error", self.on_error);
+        node.children = self.children.iter().map(|child| child.ast()).collect();
+        node.else_children = self.else_children.as_ref().map(|children| children.iter().map(|child| child.ast()).collect());
+        node
+    }
+}
+
+impl LoopTemplateNode {
+    /// Fetches the document, and selects the values to iterate.
+    fn select_values(&self, context: &Option<HashMap<String,String>>, doc_url: &str) -> Result<Vec<LoopItem>, CommonError> {
+        let html_text = get_doc(context, &self.source, &self.doc_builder)?;
+        let mut items = self.select_items(&html_text)?;
+        if self.absolute_url {
+            items.iter_mut().for_each(|item| item.value = resolve_url(doc_url, &item.value));
+        }
+        let matches = items.len();
+        let values = self.refinement.apply(items);
+        log::trace!("loop '{}' at {}: {} match(es), iterates {} value(s) as '{}'", self.selector, doc_url, matches, values.len(), self.var_name);
+        self.doc_builder.sandbox().check_loop_iterations(values.len())?;
+        if values.is_empty() && self.required {
+            return Err(Box::new(MissingValueError::new(format!("no value is selected by the required loop selector '{}' at: {}", self.selector, doc_url))));
+        }
+        Ok(values)
+    }
+
+    fn render_iteration(&self, context: &HashMap<String,String>) -> Result<Buffer, CommonError> {
+        let mut iteration_buff = Buffer::default();
+        for node in &self.children {
+            node.evaluate(Some(context.clone()), &mut iteration_buff)?
+        }
+        Ok(iteration_buff)
+    }
+
+    /// Writes the iteration rendered aside, or recovers from its failure by the `on-error`.
+    fn write_iteration(&self, iteration_buff: Result<Buffer, CommonError>, on_error: OnError, rendered_count: &mut usize, out: &mut dyn Output) -> Result<(), CommonError> {
+        let buff = match iteration_buff {
+            Ok(buff) => buff,
+            Err(error) => match self.doc_builder.recover(on_error, error)? {
+                Some(placeholder) => {
+                    let mut buff = Buffer::default();
+                    buff.write_str(placeholder)?;
+                    buff
+                },
+                None => return Ok(())
+            }
+        };
+        self.push_separator(*rendered_count, out)?;
+        buff.write_to(out)?;
+        *rendered_count += 1;
+        self.doc_builder.sandbox().check_output(out.written())?;
+        out.flush()
+    }
+
+    fn push_separator(&self, index: usize, out: &mut dyn Output) -> Result<(), CommonError> {
+        match &self.separator {
+            Some(separator) if index > 0 => out.write_str(separator),
+            _ => Ok(())
+        }
+    }
+
+    fn select_items(&self, html_text: &str) -> Result<Vec<LoopItem>, CommonError> {
+        match self.selector_kind {
+            SelectorKind::Css => {
+                let css_doc = self.doc_builder.build_doc(html_text);
+                // The index is of the node among the matches, including the nodes without the property.
+                let selected_values = match &self.node_property {
+                    Some(property) => css_doc.select_each_prop(&self.selector, property)?,
+                    None => css_doc.select_all(&self.selector)?.unwrap_or_default().into_iter().map(Some).collect()
+                };
+                Ok(selected_values.into_iter().enumerate().filter_map(|(index, value)| value.map(|value| LoopItem{value, index, bindings: HashMap::new()})).collect())
+            },
+            SelectorKind::Regex => {
+                let regex_doc = self.doc_builder.build_regex_doc(html_text);
+                let group = self.node_property.as_deref().unwrap_or("0");
+                Ok(regex_doc.select_all_captures(&self.selector, group)?.into_iter().enumerate().map(|(index, (value, named))| {
+                    let bindings = named.into_iter().map(|(name, value)| (format!("{}.{}", self.var_name, name), value)).collect();
+                    LoopItem{value, index, bindings}
+                }).collect())
+            },
+            SelectorKind::Table => {
+                let table = match self.doc_builder.build_html_doc(html_text).table(&self.selector)? {
+                    Some(table) => table,
+                    None => return Ok(Vec::new())
+                };
+                let columns = table.columns;
+                Ok(table.rows.into_iter().enumerate().map(|(index, row)| {
+                    let mut bindings = HashMap::new();
+                    for (column_index, (column, cell)) in columns.iter().zip(row.iter()).enumerate() {
+                        bindings.insert(format!("{}.{}", self.var_name, column_index + 1), cell.clone());
+                        bindings.insert(format!("{}.{}", self.var_name, column), cell.clone());
+                    }
+                    LoopItem{value: row.join("\t"), index, bindings}
+                }).collect())
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
-struct EndTemplateNode;
+struct EndTemplateNode {
+    span: Range<usize>
+}
 
 impl TemplateNode for EndTemplateNode {
-    fn evaluate(&self, _context: Option<HashMap<String,String>>, _buff: &mut String) -> Result<(), CommonError> {
+    fn evaluate(&self, _context: Option<HashMap<String,String>>, _out: &mut dyn Output) -> Result<(), CommonError> {
         Ok(())
     }
 
     fn is_end(&self) -> bool {
         true
     }
+
+    fn ast(&self) -> AstNode {
+        AstNode::new("end", self.span.clone())
+    }
+}
+
+#[derive(Debug)]
+struct ElseTemplateNode {
+    span: Range<usize>
+}
+
+impl TemplateNode for ElseTemplateNode {
+    fn evaluate(&self, _context: Option<HashMap<String,String>>, _out: &mut dyn Output) -> Result<(), CommonError> {
+        Ok(())
+    }
+
+    fn is_else(&self) -> bool {
+        true
+    }
+
+    fn ast(&self) -> AstNode {
+        AstNode::new("else", self.span.clone())
+    }
 }
 
 #[derive(Debug)]
 struct VarTemplateNode {
-    var_name: String
+    var_name: String,
+    span: Range<usize>
 }
 
 impl TemplateNode for VarTemplateNode {
-    fn evaluate(&self, context: Option<HashMap<String,String>>, buff: &mut String) -> Result<(), CommonError> {
+    fn evaluate(&self, context: Option<HashMap<String,String>>, out: &mut dyn Output) -> Result<(), CommonError> {
         match context {
             Some(var_context) => {
                 match var_context.get(&self.var_name) {
-                    Some(var_val) => buff.push_str(var_val),
-                    None => {panic!("Unable to find the context for the 'var' node")}
+                    Some(var_val) => {
+                        log::trace!("var '{}': emits {:?}", self.var_name, var_val);
+                        let start = out.written();
+                        out.write_str(var_val)?;
+                        out.record(Provenance{
+                            output: start..out.written(),
+                            template: self.span.clone(),
+                            url: var_context.get(&doc_url_key(&self.var_name)).cloned(),
+                            selector: var_context.get(&selector_key(&self.var_name)).cloned(),
+                            node_index: var_context.get(&node_index_key(&self.var_name)).and_then(|x| x.parse().ok()),
+                            timestamp: SystemTime::now()
+                        })?;
+                    },
+                    None => return Err(Box::new(TemplateError::new(format!("unable to find the variable '{}' in the context", self.var_name))))
                 }
             },
-            None => panic!("Unable to find the context for the 'var' node")
+            None => return Err(Box::new(TemplateError::new(format!("unable to find the variable '{}' in the context", self.var_name))))
         }
         Ok(())
     }
+
+    fn ast(&self) -> AstNode {
+        let mut node = AstNode::new("var", self.span.clone());
+        node.attribute("name", Some(&self.var_name));
+        node
+    }
 }
 
 #[cfg(test)]
@@ -207,7 +1086,46 @@ mod tests {
     use super::parse;
     use crate::mock::InternalNode;
     use crate::template_parser::DocBuilder;
+    use crate::fetch::{Fetcher, RequestOptions};
+    use super::CommonError;
+    use super::output::{Output, Provenance};
     use std::collections::HashMap;
+    use std::time::Duration;
+
+    /// The output of the tests, which keeps what is written at each flush, and how many documents
+    /// are fetched by then if it is given the doc builder. It also keeps the provenance of the values.
+    #[derive(Default)]
+    struct TestOutput<'a> {
+        text: String,
+        flushed: Vec<String>,
+        fetched: Vec<usize>,
+        records: Vec<Provenance>,
+        doc_builder: Option<&'a DocBuilder>
+    }
+
+    impl Output for TestOutput<'_> {
+        fn write_str(&mut self, text: &str) -> Result<(), CommonError> {
+            self.text.push_str(text);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), CommonError> {
+            self.flushed.push(self.text.clone());
+            if let Some(doc_builder) = self.doc_builder {
+                self.fetched.push(doc_builder.fetcher().requests().len());
+            }
+            Ok(())
+        }
+
+        fn written(&self) -> usize {
+            self.text.len()
+        }
+
+        fn record(&mut self, provenance: Provenance) -> Result<(), CommonError> {
+            self.records.push(provenance);
+            Ok(())
+        }
+    }
 
     #[test]
     fn test_css_property() {
@@ -240,6 +1158,420 @@ mod tests {
         assert_eq!(result, "The authors are: Mr. James Ma Mr. Alex Wang ");
     }
 
+    #[test]
+    fn test_loop_metadata() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("li", vec![InternalNode::new("Alberta"), InternalNode::new("Quebec"), InternalNode::new("Yukon")]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let result = parse("{{loop selector='li' var='p' doc='https://mock'}}{{var loop.index}}/{{var loop.length}}:{{var p}}({{var loop.index0}},{{var loop.first}},{{var loop.last}}) {{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "1/3:Alberta(0,true,false) 2/3:Quebec(1,false,false) 3/3:Yukon(2,false,true) ");
+    }
+
+    #[test]
+    fn test_loop_separator() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("li", vec![InternalNode::new("Alberta"), InternalNode::new("Quebec")]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let result = parse("[{{loop selector='li' var='p' separator=', ' doc='https://mock'}}\"{{var p}}\"{{end}}]", &doc_builder).unwrap();
+        assert_eq!(result, "[\"Alberta\", \"Quebec\"]");
+    }
+
+    #[test]
+    fn test_loop_refinement() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("li", ["item 10", "item 2", "other", "item 1", "item 2"].iter().map(|x| InternalNode::new(x)).collect());
+        let doc_builder = DocBuilder::from(mock_data);
+        let render = |attributes: &str| parse(&format!("{{{{loop selector='li' var='v' separator='|' doc='https://mock' {}}}}}{{{{var v}}}}{{{{end}}}}", attributes), &doc_builder).unwrap();
+        assert_eq!(render(""), "item 10|item 2|other|item 1|item 2");
+        assert_eq!(render("limit=2"), "item 10|item 2");
+        assert_eq!(render("offset=3"), "item 1|item 2");
+        assert_eq!(render("step=2"), "item 10|other|item 2");
+        assert_eq!(render("reverse"), "item 2|item 1|other|item 2|item 10");
+        assert_eq!(render("unique"), "item 10|item 2|other|item 1");
+        assert_eq!(render("sort='asc'"), "item 1|item 10|item 2|item 2|other");
+        assert_eq!(render("sort='desc'"), "other|item 2|item 2|item 10|item 1");
+        assert_eq!(render("sort='natural' unique"), "item 1|item 2|item 10|other");
+        assert_eq!(render("where='^item \\d$' unique reverse limit=1"), "item 1");
+    }
+
+    #[test]
+    fn test_loop_else() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("li", vec![InternalNode::new("Alberta")]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let result = parse("{{loop selector='li' var='p' doc='https://mock'}}{{var p}}{{else}}No results{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "Alberta");
+        let result = parse("{{loop selector='p' var='p' doc='https://mock'}}{{var p}}{{else}}No results{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "No results");
+        let result = parse("{{loop selector='li' var='p' where='^Q' doc='https://mock'}}{{var p}}{{else}}No results{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "No results");
+        assert!(parse("{{loop selector='li' var='p' doc='https://mock'}}{{else}}{{else}}{{end}}", &doc_builder).is_err());
+        assert!(parse("{{else}}", &doc_builder).is_err());
+    }
+
+    #[test]
+    fn test_loop_required() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("li", vec![InternalNode::new("Alberta")]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let result = parse("{{loop selector='li' var='p' required doc='https://mock'}}{{var p}}{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "Alberta");
+        let error = parse("{{loop selector='div.missing' var='p' required doc='https://mock'}}{{var p}}{{end}}", &doc_builder).unwrap_err();
+        assert_eq!(error.to_string(), "[ERROR] no value is selected by the required loop selector 'div.missing' at: https://mock");
+    }
+
+    #[test]
+    fn test_regex() {
+        let doc_builder = DocBuilder::from_text("<script>var price = 12.99; var discount = 0.5;</script>");
+        let result = parse("Price: {{regex pattern='var price = ([\\d.]+);' group=1 doc='https://mock'}}", &doc_builder).unwrap();
+        assert_eq!(result, "Price: 12.99");
+        let result = parse("Tax: {{regex pattern='var tax = ([\\d.]+);' group=1 default='N/A' doc='https://mock'}}", &doc_builder).unwrap();
+        assert_eq!(result, "Tax: N/A");
+        assert!(parse("{{regex pattern='var tax' doc='https://mock'}}", &doc_builder).is_err());
+    }
+
+    #[test]
+    fn test_loop_regex() {
+        let doc_builder = DocBuilder::from_text("<script>var price = 12.99; var discount = 0.5;</script>");
+        let result = parse("{{loop pattern='var (?P<name>\\w+) = (?P<value>[\\d.]+);' var='v' separator=', ' doc='https://mock'}}{{var v.name}}: {{var v.value}}{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "price: 12.99, discount: 0.5");
+        let result = parse("{{loop pattern='var \\w+ = ([\\d.]+);' node-property='1' var='v' sort='natural' separator=', ' doc='https://mock'}}{{var v}}{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "0.5, 12.99");
+    }
+
+    #[test]
+    fn test_meta() {
+        let doc_builder = DocBuilder::from_text(r#"<head><meta property="og:title" content="A Pen"></head><div itemscope itemtype="https://schema.org/Product"><meta itemprop="price" content="12.99"></div>"#);
+        let result = parse("{{meta name='og:title' doc='https://mock'}}: {{meta name='price' type='Product' doc='https://mock'}} {{meta name='og:image' default='N/A' doc='https://mock'}}", &doc_builder).unwrap();
+        assert_eq!(result, "A Pen: 12.99 N/A");
+        assert!(parse("{{meta name='og:image' doc='https://mock'}}", &doc_builder).is_err());
+    }
+
+    #[test]
+    fn test_json_ld() {
+        let doc_builder = DocBuilder::from_text(r#"<script type="application/ld+json">[{"@type": "Organization", "name": "ACME"}, {"@type": "Product", "name": "Pen", "offers": {"price": 12.99}}]</script>"#);
+        let result = parse("{{jsonld path='$.name' doc='https://mock'}}, {{jsonld path='$.name' type='Product' doc='https://mock'}}: {{jsonld path='$.offers.price' doc='https://mock'}}", &doc_builder).unwrap();
+        assert_eq!(result, "ACME, Pen: 12.99");
+        let result = parse("{{jsonld path='$.sku' default='N/A' doc='https://mock'}}", &doc_builder).unwrap();
+        assert_eq!(result, "N/A");
+        assert!(parse("{{jsonld path='$.sku' doc='https://mock'}}", &doc_builder).is_err());
+    }
+
+    #[test]
+    fn test_table() {
+        let doc_builder = DocBuilder::from_text(r#"<table class="stats">
+            <tr><th>Province</th><th>Population 2021</th></tr>
+            <tr><td>Quebec</td><td>8,501,833</td></tr>
+            <tr><td>Alberta</td><td>4,262,635</td></tr>
+            </table>"#);
+        let result = parse("{{table selector='table.stats' var='row' sort='asc' separator='; ' doc='https://mock'}}{{var row.Province}}: {{var 'row.Population 2021'}}{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "Alberta: 4,262,635; Quebec: 8,501,833");
+        let result = parse("{{table selector='table.stats' var='row' limit=1 doc='https://mock'}}{{var row.1}}|{{var row}}{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "Quebec|Quebec\t8,501,833");
+        let result = parse("{{table selector='table.missing' var='row' doc='https://mock'}}{{var row}}{{else}}No table{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "No table");
+    }
+
+    #[test]
+    fn test_request_options() {
+        let fetcher = Fetcher::new(RequestOptions{headers: vec![(String::from("user-agent"), String::from("Mozilla/5.0"))], ..Default::default()});
+        let doc_builder = DocBuilder::from(HashMap::new()).with_fetcher(fetcher);
+        parse("{{css selector='p' default='' doc='https://mock/search' method='post' header-accept='text/html' header-user-agent='curl' form-q='canada'}}", &doc_builder).unwrap();
+        let requests = doc_builder.fetcher().requests();
+        assert_eq!(requests[0].0, "https://mock/search");
+        let options = &requests[0].1;
+        assert_eq!(options.method(), "POST");
+        assert_eq!(options.headers, vec![(String::from("accept"), String::from("text/html")), (String::from("user-agent"), String::from("curl"))]);
+        assert_eq!(options.form, vec![(String::from("q"), String::from("canada"))]);
+        parse("{{css selector='p' default='' doc='https://mock/api' json-body='{\"q\": 1}' timeout='2.5' retries=1 retry-delay=0}}", &doc_builder).unwrap();
+        let requests = doc_builder.fetcher().requests();
+        assert_eq!(requests[1].1.method(), "POST");
+        assert_eq!(requests[1].1.json_body, Some(String::from("{\"q\": 1}")));
+        assert_eq!(requests[1].1.timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(requests[1].1.retries, Some(1));
+        assert_eq!(requests[1].1.retry_delay, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        use super::natural_cmp;
+        use std::cmp::Ordering;
+        assert_eq!(natural_cmp("item 2", "item 10"), Ordering::Less);
+        assert_eq!(natural_cmp("item 02", "item 2"), Ordering::Equal);
+        assert_eq!(natural_cmp("b", "a10"), Ordering::Greater);
+        assert_eq!(natural_cmp("a", "a1"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_concurrent_doc_var() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("a", (1..=8).map(|x| InternalNode::new(&format!("https://mock/{}", x))).collect());
+        mock_data.insert("h1", vec![InternalNode::new("Title")]);
+        let doc_builder = DocBuilder::from(mock_data).with_concurrency(3);
+        let result = parse("{{loop selector='a' var='url' separator=',' doc='https://mock'}}{{var loop.index}}:{{css selector='h1' doc-var='url'}}{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "1:Title,2:Title,3:Title,4:Title,5:Title,6:Title,7:Title,8:Title");
+        let mut urls: Vec<String> = doc_builder.fetcher().requests().into_iter().map(|(url, _)| url).collect();
+        urls.sort();
+        assert_eq!(urls.len(), 9);
+        assert_eq!(urls[0], "https://mock");
+    }
+
+    #[test]
+    fn test_plan() {
+        let doc_builder = DocBuilder::from(HashMap::new());
+        let template = "{{loop selector='a' var='url' doc='/list' base-doc='https://mock'}}{{css selector='h1' doc-var='url'}}{{css selector='p' doc='https://mock/about' form-q='x'}}{{end}}{{css selector='p' doc='https://mock/about' form-q='x'}}";
+        let plan = super::plan(template, &doc_builder).unwrap();
+        assert_eq!(plan.to_string(), "GET https://mock/list\nPOST https://mock/about\nGET {doc-var 'url'}\n");
+        assert!(doc_builder.fetcher().requests().is_empty());
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("li", vec![InternalNode::new("Alberta"), InternalNode::new("Quebec")]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let result = parse("{{loop selector='li' var='p' doc='https://mock/a'}}{{var p}}:{{css selector='li' doc='https://mock/b'}} {{end}}{{css selector='li' doc='https://mock/a'}}", &doc_builder).unwrap();
+        assert_eq!(result, "Alberta:Alberta Quebec:Alberta Alberta");
+        let mut urls: Vec<String> = doc_builder.fetcher().requests().into_iter().map(|(url, _)| url).collect();
+        urls.sort();
+        assert_eq!(urls, vec!["https://mock/a", "https://mock/b"]);
+    }
+
+    #[test]
+    fn test_resolve_url() {
+        use super::resolve_url;
+        let base = "https://www.statcan.gc.ca/en/reference/province.html?lang=en";
+        assert_eq!(resolve_url(base, "../page"), "https://www.statcan.gc.ca/en/page");
+        assert_eq!(resolve_url(base, "alberta.html"), "https://www.statcan.gc.ca/en/reference/alberta.html");
+        assert_eq!(resolve_url(base, "/absolute"), "https://www.statcan.gc.ca/absolute");
+        assert_eq!(resolve_url(base, "?page=2"), "https://www.statcan.gc.ca/en/reference/province.html?page=2");
+        assert_eq!(resolve_url(base, "#top"), "https://www.statcan.gc.ca/en/reference/province.html?lang=en#top");
+        assert_eq!(resolve_url(base, "//cdn.statcan.gc.ca/a.png"), "https://cdn.statcan.gc.ca/a.png");
+        assert_eq!(resolve_url(base, " https://example.com "), "https://example.com");
+        assert_eq!(resolve_url(base, "mailto:info@statcan.gc.ca"), "mailto:info@statcan.gc.ca");
+        assert_eq!(resolve_url("not a url", "/page"), "/page");
+    }
+
+    #[test]
+    fn test_relative_doc_var() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        let mut link = InternalNode::new("");
+        link.set_prop("href", "../b/page?x=1");
+        mock_data.insert("a", vec![link]);
+        let doc_builder = DocBuilder::from(mock_data);
+        parse("{{loop selector='a' node-property='href' var='url' doc='https://mock/a/index.html'}}{{css selector='p' default='' doc-var='url'}}{{css selector='p' default='' doc-var='url' base-doc='https://other/c/'}}{{end}}", &doc_builder).unwrap();
+        let urls: Vec<String> = doc_builder.fetcher().requests().into_iter().map(|(url, _)| url).collect();
+        assert_eq!(urls, vec!["https://mock/a/index.html", "https://mock/b/page?x=1", "https://other/b/page?x=1"]);
+        let result = parse("{{css selector='a' node-property='href' absolute-url doc='https://mock/a/'}} {{loop selector='a' node-property='href' absolute-url var='url' doc='https://mock/a/'}}{{var url}}{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "https://mock/b/page?x=1 https://mock/b/page?x=1");
+    }
+
+    #[test]
+    fn test_sandbox() {
+        use super::sandbox::{Sandbox, SandboxPolicy};
+        use std::sync::Arc;
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("a", vec![InternalNode::new("https://mock/1"), InternalNode::new("https://other/2"), InternalNode::new("https://mock/3")]);
+        let sandboxed = |policy: SandboxPolicy| DocBuilder::from(mock_data.clone()).with_sandbox(Arc::new(Sandbox::new(policy)));
+        let template = "{{loop selector='a' var='url' doc='https://mock'}}{{css selector='a' doc-var='url'}},{{end}}";
+        let doc_builder = sandboxed(SandboxPolicy{allowed_hosts: vec![String::from("mock")], ..Default::default()});
+        assert_eq!(parse(template, &doc_builder).unwrap_err().to_string(), "[SANDBOX ERROR] the host of the url is not allowed: https://other/2");
+        assert!(doc_builder.fetcher().requests().iter().all(|(url, _)| url.starts_with("https://mock")));
+        let doc_builder = sandboxed(SandboxPolicy{max_fetches: Some(3), ..Default::default()});
+        assert_eq!(parse(template, &doc_builder).unwrap_err().to_string(), "[SANDBOX ERROR] the template fetches more than 3 documents");
+        assert_eq!(doc_builder.fetcher().requests().len(), 3);
+        // The documents that are never used, e.g. in an `else` branch, are not counted.
+        let unreached = "{{loop selector='a' var='url' doc='https://mock'}}{{var url}}{{else}}{{css selector='a' doc='https://mock/else'}}{{end}}{{css selector='a' doc='https://mock/c'}}";
+        let doc_builder = sandboxed(SandboxPolicy{max_fetches: Some(2), ..Default::default()});
+        assert!(parse(unreached, &doc_builder).is_ok());
+        assert_eq!(doc_builder.fetcher().requests().len(), 2);
+        let doc_builder = sandboxed(SandboxPolicy{max_fetches: Some(1), ..Default::default()});
+        assert_eq!(parse(unreached, &doc_builder).unwrap_err().to_string(), "[SANDBOX ERROR] the template fetches more than 1 documents");
+        let doc_builder = sandboxed(SandboxPolicy{max_loop_iterations: Some(2), ..Default::default()});
+        assert_eq!(parse(template, &doc_builder).unwrap_err().to_string(), "[SANDBOX ERROR] the loops of the template iterate more than 2 times");
+        let doc_builder = sandboxed(SandboxPolicy{max_nesting_depth: Some(1), ..Default::default()});
+        assert!(parse(template, &doc_builder).is_ok());
+        let nested = "{{loop selector='a' var='x' doc='https://mock'}}{{loop selector='a' var='y' doc='https://mock'}}{{end}}{{end}}";
+        assert_eq!(parse(nested, &doc_builder).unwrap_err().to_string(), "[SANDBOX ERROR] the loops of the template are nested deeper than 1");
+        let doc_builder = sandboxed(SandboxPolicy{max_output_bytes: Some(20), ..Default::default()});
+        assert_eq!(parse(template, &doc_builder).unwrap_err().to_string(), "[SANDBOX ERROR] the output is larger than 20 bytes");
+        let doc_builder = sandboxed(SandboxPolicy{time_budget: Some(Duration::ZERO), ..Default::default()});
+        assert!(parse(template, &doc_builder).unwrap_err().to_string().starts_with("[SANDBOX ERROR] the render takes longer than"));
+    }
+
+    #[test]
+    fn test_render_flushes_per_iteration() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("li", vec![InternalNode::new("Alberta"), InternalNode::new("Quebec")]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let mut output = TestOutput::default();
+        super::render("[{{loop selector='li' var='p' separator=',' doc='https://mock'}}{{var p}}{{end}}]", &doc_builder, &mut output).unwrap();
+        assert_eq!(output.flushed, vec!["[Alberta", "[Alberta,Quebec", "[Alberta,Quebec]"]);
+    }
+
+    #[test]
+    fn test_render_streams_fetching_iterations() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("a", vec![InternalNode::new("https://mock/1"), InternalNode::new("https://mock/2"), InternalNode::new("https://mock/3")]);
+        let template = "{{loop selector='a' var='url' separator=',' doc='https://mock'}}{{css selector='a' doc-var='url'}}{{end}}";
+        // Each iteration is written before the next one fetches its document.
+        let doc_builder = DocBuilder::from(mock_data.clone()).with_concurrency(1);
+        let mut output = TestOutput{doc_builder: Some(&doc_builder), ..Default::default()};
+        super::render(template, &doc_builder, &mut output).unwrap();
+        assert_eq!(output.flushed[..3], ["https://mock/1", "https://mock/1,https://mock/1", "https://mock/1,https://mock/1,https://mock/1"]);
+        assert_eq!(output.fetched[..3], [2, 3, 4]);
+
+        // The concurrent iterations are written in order, each of them flushed.
+        let doc_builder = DocBuilder::from(mock_data).with_concurrency(4);
+        let mut output = TestOutput{doc_builder: Some(&doc_builder), ..Default::default()};
+        super::render(template, &doc_builder, &mut output).unwrap();
+        assert_eq!(output.flushed[..3], ["https://mock/1", "https://mock/1,https://mock/1", "https://mock/1,https://mock/1,https://mock/1"]);
+    }
+
+    #[test]
+    fn test_provenance() {
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("a", vec![InternalNode::new("https://mock/1"), InternalNode::new("https://mock/2")]);
+        mock_data.insert("h1", vec![InternalNode::new("Title")]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let mut output = TestOutput::default();
+        let template = "{{loop selector='a' var='url' separator=',' reverse doc='https://mock'}}{{var url}}={{css selector='h1' doc-var='url'}}{{end}}";
+        super::render(template, &doc_builder, &mut output).unwrap();
+        assert_eq!(output.text, "https://mock/2=Title,https://mock/1=Title");
+        let records: Vec<_> = output.records.iter()
+            .map(|x| (&output.text[x.output.clone()], &template[x.template.clone()], x.url.as_deref(), x.selector.as_deref(), x.node_index))
+            .collect();
+        assert_eq!(records, vec![
+            ("https://mock/2", "{{var url}}", Some("https://mock"), Some("a"), Some(1)),
+            ("Title", "{{css selector='h1' doc-var='url'}}", Some("https://mock/2"), Some("h1"), Some(0)),
+            ("https://mock/1", "{{var url}}", Some("https://mock"), Some("a"), Some(0)),
+            ("Title", "{{css selector='h1' doc-var='url'}}", Some("https://mock/1"), Some("h1"), Some(0))
+        ]);
+
+        // The index of a node is among all the matches, including the nodes without the property.
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        let mut quebec = InternalNode::new("Quebec");
+        quebec.set_prop("href", "/qc");
+        mock_data.insert("a", vec![InternalNode::new("Home"), quebec]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let mut output = TestOutput::default();
+        super::render("{{loop selector='a' node-property='href' var='link' doc='https://mock'}}{{var link}}{{end}}", &doc_builder, &mut output).unwrap();
+        assert_eq!(output.text, "/qc");
+        assert_eq!(output.records.iter().map(|x| x.node_index).collect::<Vec<_>>(), vec![Some(1)]);
+    }
+
+    #[test]
+    fn test_on_error() {
+        use super::OnError;
+        use crate::fetch::Response;
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("a", vec![InternalNode::new("https://mock/1"), InternalNode::new("https://mock/broken"), InternalNode::new("https://mock/3")]);
+        mock_data.insert("h1", vec![InternalNode::new("Title")]);
+        let with_on_error = |on_error: OnError| {
+            let fetcher = Fetcher::new(RequestOptions::default()).with_response("https://mock/broken", Response{status: 404, headers: Vec::new(), body: Vec::new()});
+            DocBuilder::from(mock_data.clone()).with_fetcher(fetcher).with_on_error(on_error)
+        };
+        let template = "{{loop selector='a' var='url' separator=',' doc='https://mock'}}<{{css selector='h1' doc-var='url'}}>{{end}}";
+        let doc_builder = with_on_error(OnError::Abort);
+        assert!(parse(template, &doc_builder).unwrap_err().to_string().starts_with("[FETCH ERROR] failed to fetch the document at: https://mock/broken"));
+        let doc_builder = with_on_error(OnError::Skip);
+        assert_eq!(parse(template, &doc_builder).unwrap(), "<Title>,<Title>");
+        assert_eq!(doc_builder.errors().len(), 1);
+        let doc_builder = with_on_error(OnError::Placeholder).with_concurrency(1);
+        assert_eq!(parse(template, &doc_builder).unwrap(), "<Title>,[ERROR],<Title>");
+        // The token's own `on-error` takes precedence.
+        let doc_builder = with_on_error(OnError::Skip);
+        let result = parse("{{loop selector='a' var='url' separator=',' doc='https://mock'}}<{{css selector='h1' doc-var='url' on-error='placeholder'}}>{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "<Title>,<[ERROR]>,<Title>");
+        let doc_builder = with_on_error(OnError::Abort);
+        let result = parse("A{{loop selector='a' var='url' on-error='skip' doc='https://mock/broken'}}{{var url}}{{end}}B{{css selector='h1' doc='https://mock/broken' on-error='placeholder'}}", &doc_builder).unwrap();
+        assert_eq!(result, "AB[ERROR]");
+        assert_eq!(doc_builder.errors().len(), 2);
+        let result = parse("{{loop selector='p' var='p' required on-error='skip' doc='https://mock'}}{{var p}}{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "");
+    }
+
+    #[test]
+    fn test_error_kind() {
+        use super::ErrorKind;
+        use super::sandbox::{Sandbox, SandboxPolicy};
+        use std::sync::Arc;
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("h1", vec![InternalNode::new("Title")]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let kind = |template: &str| ErrorKind::of(&parse(template, &doc_builder).unwrap_err());
+        assert_eq!(kind("{{css selector='h1' doc='https://mock'}}{{end}}"), ErrorKind::Template);
+        assert_eq!(kind("{{else}}"), ErrorKind::Template);
+        assert_eq!(kind("{{css selector='h2' doc='https://mock'}}"), ErrorKind::MissingValue);
+        assert_eq!(kind("{{loop selector='h2' var='x' required doc='https://mock'}}{{end}}"), ErrorKind::MissingValue);
+        let fetcher = Fetcher::new(RequestOptions::default()).with_response("https://mock/broken", crate::fetch::Response{status: 404, headers: Vec::new(), body: Vec::new()});
+        let doc_builder = DocBuilder::from(HashMap::new()).with_fetcher(fetcher);
+        assert_eq!(ErrorKind::of(&parse("{{css selector='h1' doc='https://mock/broken'}}", &doc_builder).unwrap_err()), ErrorKind::Fetch);
+        let doc_builder = DocBuilder::from(HashMap::new()).with_sandbox(Arc::new(Sandbox::new(SandboxPolicy{max_fetches: Some(0), ..Default::default()})));
+        assert_eq!(ErrorKind::of(&parse("{{css selector='h1' doc='https://mock'}}", &doc_builder).unwrap_err()), ErrorKind::Sandbox);
+        let doc_builder = DocBuilder::from_text("<table><tr><td>Pen</td></tr></table><script type='application/ld+json'>{\"name\": \"Pen\"}</script>");
+        let kind = |template: &str| ErrorKind::of(&parse(template, &doc_builder).unwrap_err());
+        assert_eq!(kind("{{table selector='table[' var='row' doc='https://mock'}}{{end}}"), ErrorKind::Template);
+        assert_eq!(kind("{{regex pattern='(' doc='https://mock'}}"), ErrorKind::Template);
+        assert_eq!(kind("{{jsonld path='name' doc='https://mock'}}"), ErrorKind::Template);
+    }
+
+    #[test]
+    fn test_trace() {
+        use std::sync::Mutex;
+        struct TestLogger(Mutex<Vec<String>>);
+        impl log::Log for TestLogger {
+            fn enabled(&self, _metadata: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+            fn flush(&self) {}
+        }
+        static LOGGER: TestLogger = TestLogger(Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        mock_data.insert("li", vec![InternalNode::new("Alberta"), InternalNode::new("Quebec")]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let result = parse("{{loop selector='li' var='p' limit=1 doc='https://mock/trace'}}{{css selector='li' doc='https://mock/trace'}}{{end}}", &doc_builder).unwrap();
+        assert_eq!(result, "Alberta");
+        let records: Vec<String> = LOGGER.0.lock().unwrap().iter().filter(|x| x.contains("https://mock/trace")).cloned().collect();
+        assert_eq!(records.len(), 5);
+        assert!(records[0].starts_with("GET https://mock/trace: status 200, 0 bytes in "));
+        assert!(records[1].starts_with("document https://mock/trace: 0 bytes in "));
+        assert_eq!(records[2], "loop 'li' at https://mock/trace: 2 match(es), iterates 1 value(s) as 'p'");
+        assert!(records[3].starts_with("document https://mock/trace: 0 bytes in "));
+        assert_eq!(records[4], "css 'li' at https://mock/trace: 2 match(es), emits \"Alberta\"");
+
+        // The matches of a css with 'node-property' include the nodes without the property.
+        let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
+        let mut alberta = InternalNode::new("Alberta");
+        alberta.set_prop("href", "/ab");
+        mock_data.insert("a", vec![InternalNode::new("Home"), alberta]);
+        let doc_builder = DocBuilder::from(mock_data);
+        let template = "{{css selector='a' node-property='href' default='none' doc='https://mock/trace2'}}\
+            {{loop selector='a' var='trace_p' doc='https://mock/trace2'}}{{var trace_p}}{{end}}";
+        assert_eq!(parse(template, &doc_builder).unwrap(), "noneHomeAlberta");
+        let page = "<meta property='og:title' content='Pen'><script type='application/ld+json'>{\"name\": \"Pen\"}</script>";
+        let doc_builder = DocBuilder::from_text(page);
+        let template = "{{regex pattern='Pen' doc='https://mock/trace2'}}{{meta name='og:title' doc='https://mock/trace2'}}{{jsonld path='$.name' doc='https://mock/trace2'}}";
+        assert_eq!(parse(template, &doc_builder).unwrap(), "PenPenPen");
+        let records: Vec<String> = LOGGER.0.lock().unwrap().iter()
+            .filter(|x| (x.contains("https://mock/trace2") && !x.contains(" bytes in ")) || x.starts_with("var 'trace_p'")).cloned().collect();
+        assert_eq!(records, vec![
+            "css 'a' at https://mock/trace2: 2 match(es), emits \"none\"",
+            "loop 'a' at https://mock/trace2: 2 match(es), iterates 2 value(s) as 'trace_p'",
+            "var 'trace_p': emits \"Home\"",
+            "var 'trace_p': emits \"Alberta\"",
+            "regex 'Pen' at https://mock/trace2: emits \"Pen\"",
+            "meta 'og:title' at https://mock/trace2: emits \"Pen\"",
+            "jsonld '$.name' at https://mock/trace2: 1 item(s), emits \"Pen\""
+        ]);
+    }
+
     #[test]
     fn test_doc_var() {
         let mut mock_data: HashMap<&'static str, Vec<InternalNode>> = HashMap::new();
@@ -0,0 +1,116 @@
+use super::token::line_column;
+use serde_json::{json, Map, Value};
+use std::ops::Range;
+
+/// A node of the parsed template, with its byte range in the template. The range of a `loop`
+/// covers its children up to the end of its `{{end}}`.
+#[derive(Debug,Clone,PartialEq)]
+pub struct AstNode {
+    pub kind: &'static str,
+    pub span: Range<usize>,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<AstNode>,
+    /// The children after the `{{else}}` of a loop.
+    pub else_children: Option<Vec<AstNode>>
+}
+
+impl AstNode {
+    pub fn new(kind: &'static str, span: Range<usize>) -> Self {
+        AstNode{kind, span, attributes: Vec::new(), children: Vec::new(), else_children: None}
+    }
+
+    /// Adds the attribute if it has a value.
+    pub fn attribute(&mut self, key: &str, value: Option<impl ToString>) {
+        if let Some(value) = value {
+            self.attributes.push((String::from(key), value.to_string()));
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let attributes: Map<String, Value> = self.attributes.iter().map(|(key, value)| (key.clone(), Value::from(value.as_str()))).collect();
+        let mut node = json!({
+            "kind": self.kind,
+            "span": {"start": self.span.start, "end": self.span.end},
+            "attributes": attributes,
+            "children": self.children.iter().map(AstNode::to_json).collect::<Vec<_>>()
+        });
+        if let Some(else_children) = &self.else_children {
+            node["else"] = Value::from(else_children.iter().map(AstNode::to_json).collect::<Vec<_>>());
+        }
+        node
+    }
+
+    /// Writes the node and its children indented by their depth, e.g.
+    /// `loop 1:1-3:8 var="p" selector="li" doc="https://..."`, where the span is the line and the
+    /// column of its start and end.
+    fn write_tree(&self, template: &str, depth: usize, buff: &mut String) {
+        let (start_line, start_column) = line_column(template, self.span.start);
+        let (end_line, end_column) = line_column(template, self.span.end);
+        buff.push_str(&format!("{}{} {}:{}-{}:{}", "  ".repeat(depth), self.kind, start_line, start_column, end_line, end_column));
+        for (key, value) in &self.attributes {
+            buff.push_str(&format!(" {}={:?}", key, value));
+        }
+        buff.push('\n');
+        for child in &self.children {
+            child.write_tree(template, depth + 1, buff);
+        }
+        if let Some(else_children) = &self.else_children {
+            buff.push_str(&format!("{}else\n", "  ".repeat(depth)));
+            for child in else_children {
+                child.write_tree(template, depth + 1, buff);
+            }
+        }
+    }
+}
+
+/// Prints the nodes of the template as an indented tree.
+pub fn tree(nodes: &[AstNode], template: &str) -> String {
+    let mut buff = String::new();
+    for node in nodes {
+        node.write_tree(template, 0, &mut buff);
+    }
+    buff
+}
+
+pub fn to_json(nodes: &[AstNode]) -> Value {
+    Value::from(nodes.iter().map(AstNode::to_json).collect::<Vec<_>>())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::template_parser::{ast, builder::DocBuilder};
+    use super::{to_json, tree};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_ast() {
+        let template = "<ul>{{loop selector='li' var='p' separator=',' doc='https://mock'}}\n  {{var p}}{{else}}none{{end}}</ul>{{css selector='h1' doc='https://mock' on-error='skip'}}";
+        let nodes = ast(template, &DocBuilder::from(HashMap::new())).unwrap();
+        assert_eq!(tree(&nodes, template), "text 1:1-1:5 text=\"<ul>\"
+loop 1:5-2:31 var=\"p\" selector=\"li\" separator=\",\" doc=\"https://mock\"
+  text 1:68-2:3 text=\"\\n  \"
+  var 2:3-2:12 name=\"p\"
+else
+  text 2:20-2:24 text=\"none\"
+text 2:31-2:36 text=\"</ul>\"
+css 2:36-2:92 selector=\"h1\" doc=\"https://mock\" on-error=\"skip\"
+");
+        let json = to_json(&nodes);
+        assert_eq!(json[1]["kind"], "loop");
+        assert_eq!(json[1]["span"]["start"], 4);
+        assert_eq!(json[1]["span"]["end"], template.find("</ul>").unwrap());
+        assert_eq!(json[1]["children"][1]["attributes"]["name"], "p");
+        assert_eq!(json[1]["else"][0]["attributes"]["text"], "none");
+
+        let template = "{{loop selector='li' var='p' where='^A' unique sort='natural' reverse offset='1' step='2' limit='3' doc='https://mock'}}{{end}}\
+            {{css selector='h1' doc='https://mock' method='POST' header-accept='text/html' user-agent='bot' form-q='canada' timeout='2.5' retries='3' retry-delay='0.5' encoding='latin1'}}\
+            {{regex pattern='id=(\\d+)' doc='https://mock' method='POST' json-body='{\"q\": 1}'}}";
+        let nodes = ast(template, &DocBuilder::from(HashMap::new())).unwrap();
+        let attributes: Vec<String> = nodes.iter().map(|node| node.attributes.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join(" ")).collect();
+        assert_eq!(attributes, vec![
+            "var=p selector=li where=^A unique=true sort=natural reverse=true offset=1 step=2 limit=3 doc=https://mock",
+            "selector=h1 doc=https://mock method=POST header-accept=text/html header-user-agent=bot form-q=canada timeout=2.5 retries=3 retry-delay=0.5 encoding=latin1",
+            "pattern=id=(\\d+) doc=https://mock method=POST json-body={\"q\": 1}"
+        ]);
+    }
+}
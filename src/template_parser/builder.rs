@@ -1,17 +1,35 @@
 #[cfg(test)]
 use std::collections::HashMap;
-#[cfg(not(test))]
+use std::sync::{Arc, Mutex};
 use crate::document::css::CssDocument;
 
 use crate::document::Document;
+use crate::document::regex::RegexDocument;
+use crate::fetch::{Fetcher, FetchError, RequestOptions};
+use crate::fetch::limits::DocFormat;
+use super::{CommonError, ErrorKind, OnError, ERROR_PLACEHOLDER};
+use super::pool::{WorkerPool, DEFAULT_CONCURRENCY};
+use super::sandbox::{Sandbox, SandboxError, SandboxPolicy};
 
 #[cfg(test)]
 use crate::mock::{MockCssDocument,InternalNode};
 
+type Prefetched = (String, RequestOptions, Result<String, FetchError>);
+
 #[derive(Debug,Clone)]
 pub struct DocBuilder {
+    fetcher: Arc<Fetcher>,
+    workers: Arc<WorkerPool>,
+    sandbox: Arc<Sandbox>,
+    on_error: OnError,
+    /// The errors recovered by `on-error`, which are reported at the end of the render.
+    errors: Arc<Mutex<Vec<(ErrorKind, String)>>>,
+    /// The documents fetched before the render, with the requests they were fetched with.
+    prefetched: Arc<Mutex<Vec<Prefetched>>>,
+    #[cfg(test)]
+    value_map: HashMap<&'static str, Vec<InternalNode>>,
     #[cfg(test)]
-    value_map: HashMap<&'static str, Vec<InternalNode>>
+    text: String
 }
 
 impl DocBuilder {
@@ -25,19 +43,158 @@ impl DocBuilder {
         CssDocument::from(html)
     }
 
+    #[cfg(test)]
+    pub fn build_html_doc(&self, _html: &str) -> CssDocument {
+        CssDocument::from(&*self.text)
+    }
+
+    #[cfg(not(test))]
+    pub fn build_html_doc(&self, html: &str) -> CssDocument {
+        CssDocument::from(html)
+    }
+
+    #[cfg(test)]
+    pub fn build_regex_doc(&self, _text: &str) -> RegexDocument {
+        RegexDocument::from(&*self.text)
+    }
+
+    #[cfg(not(test))]
+    pub fn build_regex_doc(&self, text: &str) -> RegexDocument {
+        RegexDocument::from(text)
+    }
+
+    pub fn fetcher(&self) -> &Fetcher {
+        &self.fetcher
+    }
+
+    pub fn workers(&self) -> &WorkerPool {
+        &self.workers
+    }
+
+    /// Fetches the documents at the same time before the render, so that the tokens using them do
+    /// not fetch them again. A document failed by an error other than a fetch error, e.g. an invalid
+    /// url, is left to the token to fail. Nothing is prefetched if the sandbox limits the number of
+    /// the fetches, because a planned document may never be used, e.g. in an `else` branch, and
+    /// only the documents used by the render are counted.
+    pub fn prefetch(&self, docs: &[(String, RequestOptions)]) {
+        if self.sandbox.limits_fetches() {
+            return;
+        }
+        let docs = {
+            let prefetched = self.prefetched.lock().unwrap();
+            docs.iter()
+                .filter(|(url, request)| !prefetched.iter().any(|(prefetched_url, prefetched_request, _)| prefetched_url == url && prefetched_request == request))
+                .filter(|(url, _)| self.sandbox.check_request(url).is_ok())
+                .cloned()
+                .collect()
+        };
+        let fetched = self.workers.map(docs, |(url, request)| {
+            let result = self.fetcher.fetch(&url, &request, self.sandbox.deadline());
+            (url, request, result)
+        });
+        let mut prefetched = self.prefetched.lock().unwrap();
+        for (url, request, result) in fetched {
+            match result {
+                Ok(text) => prefetched.push((url, request, Ok(text))),
+                Err(error) => if let Some(error) = error.downcast_ref::<FetchError>() {
+                    prefetched.push((url, request, Err(error.clone())));
+                }
+            }
+        }
+    }
+
+    /// Takes the text as the document of the url, read as HTML or as text, e.g. a page fetched once
+    /// for the REPL or a local file. The tokens using it with other request options fetch it.
+    pub fn with_document(self, url: &str, text: &str) -> Self {
+        let mut prefetched = self.prefetched.lock().unwrap();
+        for format in [DocFormat::Html, DocFormat::Text] {
+            prefetched.push((String::from(url), RequestOptions{format: Some(format), ..RequestOptions::default()}, Ok(String::from(text))));
+        }
+        drop(prefetched);
+        self
+    }
+
+    /// Fetches the document, or takes it from the prefetched documents.
+    pub fn fetch_doc(&self, url: &str, request: &RequestOptions) -> Result<String, CommonError> {
+        let prefetched = self.prefetched.lock().unwrap().iter()
+            .find(|(prefetched_url, prefetched_request, _)| prefetched_url == url && prefetched_request == request)
+            .map(|(_, _, result)| result.clone());
+        match prefetched {
+            Some(result) => result.map_err(|e| Box::new(e) as CommonError),
+            None => {
+                self.sandbox.check_fetch(url)?;
+                // A fetch cut by the time budget fails the render by the budget.
+                self.fetcher.fetch(url, request, self.sandbox.deadline()).map_err(|error| match self.sandbox.check_time() {
+                    Ok(()) => error,
+                    Err(sandbox_error) => Box::new(sandbox_error) as CommonError
+                })
+            }
+        }
+    }
+
+    pub fn on_error(&self) -> OnError {
+        self.on_error
+    }
+
+    /// Sets the `on-error` of the tokens and the loops without their own.
+    pub fn with_on_error(self, on_error: OnError) -> Self {
+        DocBuilder{on_error, ..self}
+    }
+
+    /// Recovers from the error by the `on-error`, and returns the placeholder to render if any.
+    /// The error is returned back if the render is aborted. A sandbox violation always aborts the
+    /// render, because it is not a failure of the token.
+    pub fn recover(&self, on_error: OnError, error: CommonError) -> Result<Option<&'static str>, CommonError> {
+        if on_error == OnError::Abort || error.downcast_ref::<SandboxError>().is_some() {
+            return Err(error);
+        }
+        self.errors.lock().unwrap().push((ErrorKind::of(&error), error.to_string()));
+        Ok(match on_error {
+            OnError::Placeholder => Some(ERROR_PLACEHOLDER),
+            _ => None
+        })
+    }
+
+    /// The errors recovered by `on-error` so far.
+    pub fn errors(&self) -> Vec<(ErrorKind, String)> {
+        self.errors.lock().unwrap().clone()
+    }
+
+    pub fn sandbox(&self) -> &Sandbox {
+        &self.sandbox
+    }
+
+    pub fn with_sandbox(self, sandbox: Arc<Sandbox>) -> Self {
+        DocBuilder{sandbox, ..self}
+    }
+
+    /// Renders the iterations of the loops which fetch documents with at most `concurrency` threads.
+    pub fn with_concurrency(self, concurrency: usize) -> Self {
+        DocBuilder{workers: Arc::new(WorkerPool::new(concurrency)), ..self}
+    }
+
     #[cfg(not(test))]
-    pub fn new() -> Self {
-        DocBuilder{}
+    pub fn new(fetcher: Fetcher) -> Self {
+        DocBuilder{fetcher: Arc::new(fetcher), workers: Arc::new(WorkerPool::new(DEFAULT_CONCURRENCY)), sandbox: Arc::new(Sandbox::new(SandboxPolicy::default())), on_error: OnError::Abort, errors: Arc::default(), prefetched: Arc::default()}
     }
 
     #[cfg(test)]
-    pub fn new() -> Self {
-        DocBuilder{value_map: HashMap::new()}
+    pub fn new(fetcher: Fetcher) -> Self {
+        DocBuilder{fetcher: Arc::new(fetcher), workers: Arc::new(WorkerPool::new(DEFAULT_CONCURRENCY)), sandbox: Arc::new(Sandbox::new(SandboxPolicy::default())), on_error: OnError::Abort, errors: Arc::default(), prefetched: Arc::default(), value_map: HashMap::new(), text: String::new()}
     }
 
     #[cfg(test)]
     pub fn from(value_map: HashMap<&'static str, Vec<InternalNode>>) -> Self {
-        DocBuilder{value_map}
+        DocBuilder{value_map, ..DocBuilder::new(Fetcher::new(RequestOptions::default()))}
     }
-}
 
+    #[cfg(test)]
+    pub fn from_text(text: &str) -> Self {
+        DocBuilder{text: String::from(text), ..DocBuilder::new(Fetcher::new(RequestOptions::default()))}
+    }
+
+    #[cfg(test)]
+    pub fn with_fetcher(self, fetcher: Fetcher) -> Self {
+        DocBuilder{fetcher: Arc::new(fetcher), ..self}
+    }
+}
@@ -0,0 +1,175 @@
+use super::{parse_template, LOOP_VARIABLES};
+use super::builder::DocBuilder;
+use super::token::{line_column, scan, Piece, TokenParser};
+use regex::Regex;
+use scraper::Selector;
+
+/// A problem of the template, at the line and the column(in characters) of its token.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Problem {
+    pub line: usize,
+    pub column: usize,
+    pub message: String
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// A `loop` or `table` that is open at the current token.
+struct OpenLoop {
+    keyword: String,
+    var_name: Option<String>,
+    position: usize,
+    in_else: bool
+}
+
+/// Checks the template without fetching any document, and returns all the problems found in it.
+/// The problems of a token are the ones that the token parser fails on, and the ones that the render
+/// only meets when it evaluates the token, i.e. the invalid selectors and the undefined variables.
+/// The template is parsed by `parse_template` at last if its tokens have no problem.
+pub fn check(template: &str, doc_builder: &DocBuilder) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut open_loops: Vec<OpenLoop> = Vec::new();
+    let mut report = |position: usize, message: String| problems.push(problem(template, position, message));
+    for piece in scan(template) {
+        let (range, tokens) = match piece {
+            Piece::Text(_) => continue,
+            Piece::Unclosed(position) => {
+                report(position, String::from("the token is not closed by `}}`"));
+                break;
+            },
+            Piece::Token(range, tokens) => (range, tokens)
+        };
+        let position = range.start;
+        let keyword = match tokens.first() {
+            Some(keyword) => keyword.as_str(),
+            None => {
+                report(position, String::from("the token is empty"));
+                continue;
+            }
+        };
+        for message in TokenParser::problems(&template[range.start + 2..range.end - 2], doc_builder) {
+            report(position, message);
+        }
+        match keyword {
+            "end" if open_loops.pop().is_none() => {
+                report(position, String::from("the 'end' token does not match any 'loop' or 'table' token"));
+            },
+            "else" => {
+                match open_loops.last_mut() {
+                    Some(open_loop) if open_loop.in_else => report(position, format!("duplicated 'else' token in the {}", open_loop.keyword)),
+                    Some(open_loop) => open_loop.in_else = true,
+                    None => report(position, String::from("the 'else' token must be inside a 'loop' token"))
+                }
+            },
+            "var" => {
+                if let Some(var_name) = tokens.get(1).filter(|_| tokens.len() == 2) {
+                    if !is_bound(&open_loops, var_name) {
+                        report(position, format!("the variable '{}' is not defined by any enclosing loop", var_name));
+                    }
+                }
+            },
+            "css" | "regex" | "meta" | "jsonld" | "loop" | "table" => {
+                for message in check_selectors(&tokens) {
+                    report(position, message);
+                }
+                if let Some(doc_var) = value(&tokens, "doc-var") {
+                    if !is_bound(&open_loops, &doc_var) {
+                        report(position, format!("the 'doc-var' '{}' is not defined by any enclosing loop", doc_var));
+                    }
+                }
+                if keyword == "loop" || keyword == "table" {
+                    open_loops.push(OpenLoop{keyword: String::from(keyword), var_name: value(&tokens, "var"), position, in_else: false});
+                }
+            },
+            _ => {}
+        }
+    }
+    for open_loop in open_loops {
+        report(open_loop.position, format!("the '{}' token is not closed by an 'end' token", open_loop.keyword));
+    }
+    if problems.is_empty() {
+        if let Err(error) = parse_template(template, doc_builder) {
+            problems.push(problem(template, 0, error.to_string()));
+        }
+    }
+    problems
+}
+
+fn problem(template: &str, position: usize, message: String) -> Problem {
+    let (line, column) = line_column(template, position);
+    Problem{line, column, message}
+}
+
+/// Whether the variable is defined by an enclosing loop, i.e. the variable of the loop, its
+/// bindings such as `row.Province`, or the position of the iteration such as `loop.index`. The
+/// variables of a loop are not defined in its `else` branch.
+fn is_bound(open_loops: &[OpenLoop], name: &str) -> bool {
+    open_loops.iter().filter(|x| !x.in_else).any(|open_loop| {
+        LOOP_VARIABLES.contains(&name) || open_loop.var_name.as_ref().map(|var_name| {
+            name == var_name || name.strip_prefix(var_name.as_str()).map(|x| x.starts_with('.')).unwrap_or(false)
+        }).unwrap_or(false)
+    })
+}
+
+fn value(tokens: &[String], key: &str) -> Option<String> {
+    tokens.iter().enumerate().find(|(idx, t)| *t == key && tokens.get(idx + 1).map(|x| x == "=").unwrap_or(false))
+        .and_then(|(idx, _)| tokens.get(idx + 2).cloned())
+}
+
+/// Checks the css selectors and the patterns, which the render only parses when it evaluates the token.
+fn check_selectors(tokens: &[String]) -> Vec<String> {
+    let mut messages = Vec::new();
+    if let Some(selector) = value(tokens, "selector") {
+        if let Err(e) = Selector::parse(&selector) {
+            messages.push(format!("invalid css selector '{}': {:?}", selector, e));
+        }
+    }
+    if let Some(pattern) = value(tokens, "pattern") {
+        if let Err(e) = Regex::new(&pattern) {
+            messages.push(format!("invalid 'pattern' pattern: {}", e));
+        }
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Problem};
+    use crate::template_parser::builder::DocBuilder;
+    use std::collections::HashMap;
+
+    fn messages(template: &str) -> Vec<String> {
+        check(template, &DocBuilder::from(HashMap::new())).into_iter().map(|x| x.to_string()).collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn test_check() {
+        let template = "{{loop selector='li > a' var='url' doc='https://mock'}}{{var url}}{{loop.index}}{{css selector='h1' doc-var='url'}}{{var loop.index}}{{end}}";
+        assert_eq!(messages(template), vec!["1:67: unknown keyword: loop.index"]);
+        let template = "{{table selector='table' var='row' doc='https://mock'}}{{var row.Province}}{{else}}{{var row}}{{end}}";
+        assert_eq!(messages(template), vec!["1:84: the variable 'row' is not defined by any enclosing loop"]);
+        assert!(messages("{{loop selector='li' var='p' doc='https://mock'}}{{var p}}{{end}}").is_empty());
+    }
+
+    #[test]
+    fn test_check_all_problems() {
+        let template = "{{css selector='div[' selectr='h1' doc='https://mock'}}\n{{var x}}{{css selector='h1' doc-var='url'}}\n{{loop var='p' sort='random' doc='https://mock'}}{{end}}{{end}}{{loop selector='li' var='p' doc='https://mock'}}";
+        let problems = check(template, &DocBuilder::from(HashMap::new()));
+        assert_eq!(problems.iter().map(|x| x.message.as_str()).collect::<Vec<_>>(), vec![
+            "unknown attribute 'selectr' of the css token",
+            "invalid css selector 'div[': ParseError { kind: Basic(EndOfInput), location: SourceLocation { line: 0, column: 5 } }",
+            "the variable 'x' is not defined by any enclosing loop",
+            "the 'doc-var' 'url' is not defined by any enclosing loop",
+            "unable to find `selector` in the loop token: loop var='p' sort='random' doc='https://mock'",
+            "unknown 'sort' of the loop: random, expect 'asc', 'desc' or 'natural'",
+            "the 'end' token does not match any 'loop' or 'table' token",
+            "the 'loop' token is not closed by an 'end' token"
+        ]);
+        assert_eq!(problems[2], Problem{line: 2, column: 1, message: String::from("the variable 'x' is not defined by any enclosing loop")});
+        assert_eq!(messages("{{loop selector='li' var='p' doc='https://mock'}}{{css selector='h1' doc-var='p'"), vec!["1:50: the token is not closed by `}}`", "1:1: the 'loop' token is not closed by an 'end' token"]);
+    }
+}
@@ -0,0 +1,189 @@
+use super::CommonError;
+use serde_json::json;
+use std::io::Write;
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where a value of the output came from, which is recorded by `--provenance`.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Provenance {
+    /// The byte range of the value in the output.
+    pub output: Range<usize>,
+    /// The byte range of the token in the template.
+    pub template: Range<usize>,
+    pub url: Option<String>,
+    pub selector: Option<String>,
+    /// The index of the node among the matches of the selector.
+    pub node_index: Option<usize>,
+    pub timestamp: SystemTime
+}
+
+impl Provenance {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "output": {"start": self.output.start, "end": self.output.end},
+            "template": {"start": self.template.start, "end": self.template.end},
+            "url": self.url,
+            "selector": self.selector,
+            "node_index": self.node_index,
+            "timestamp": self.timestamp.duration_since(UNIX_EPOCH).map(|x| x.as_secs_f64()).unwrap_or(0.0)
+        })
+    }
+}
+
+/// The sink that a template is rendered into, so that the output streams out as the nodes finish
+/// instead of being held until the end of the render.
+pub trait Output {
+    fn write_str(&mut self, text: &str) -> Result<(), CommonError>;
+    /// Flushes what is written so far, e.g. at the end of each loop iteration.
+    fn flush(&mut self) -> Result<(), CommonError> {
+        Ok(())
+    }
+    /// How many bytes are written so far.
+    fn written(&self) -> usize;
+    /// Records where the value that was just written came from. It is ignored by default.
+    fn record(&mut self, _provenance: Provenance) -> Result<(), CommonError> {
+        Ok(())
+    }
+}
+
+impl Output for String {
+    fn write_str(&mut self, text: &str) -> Result<(), CommonError> {
+        self.push_str(text);
+        Ok(())
+    }
+
+    fn written(&self) -> usize {
+        self.len()
+    }
+}
+
+/// The output rendered aside, e.g. of a loop iteration, along with the provenance of its values.
+#[derive(Debug,Default)]
+pub struct Buffer {
+    text: String,
+    records: Vec<Provenance>
+}
+
+impl Output for Buffer {
+    fn write_str(&mut self, text: &str) -> Result<(), CommonError> {
+        self.text.push_str(text);
+        Ok(())
+    }
+
+    fn written(&self) -> usize {
+        self.text.len()
+    }
+
+    fn record(&mut self, provenance: Provenance) -> Result<(), CommonError> {
+        self.records.push(provenance);
+        Ok(())
+    }
+}
+
+impl Buffer {
+    /// Writes the buffer into the output, where the output ranges of its records are moved along.
+    pub fn write_to(self, out: &mut dyn Output) -> Result<(), CommonError> {
+        let offset = out.written();
+        out.write_str(&self.text)?;
+        for mut provenance in self.records {
+            provenance.output = provenance.output.start + offset..provenance.output.end + offset;
+            out.record(provenance)?;
+        }
+        Ok(())
+    }
+}
+
+/// The output into a writer, e.g. the stdout or a file.
+pub struct WriteOutput<W: Write> {
+    writer: W,
+    written: usize,
+    /// Where the provenance is written as JSON lines.
+    provenance: Option<Box<dyn Write>>
+}
+
+impl<W: Write> WriteOutput<W> {
+    pub fn new(writer: W) -> Self {
+        WriteOutput{writer, written: 0, provenance: None}
+    }
+
+    pub fn with_provenance(self, provenance: impl Write + 'static) -> Self {
+        WriteOutput{provenance: Some(Box::new(provenance)), ..self}
+    }
+}
+
+impl<W: Write> Output for WriteOutput<W> {
+    fn write_str(&mut self, text: &str) -> Result<(), CommonError> {
+        self.writer.write_all(text.as_bytes())?;
+        self.written += text.len();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), CommonError> {
+        self.writer.flush()?;
+        if let Some(provenance) = &mut self.provenance {
+            provenance.flush()?;
+        }
+        Ok(())
+    }
+
+    fn written(&self) -> usize {
+        self.written
+    }
+
+    fn record(&mut self, provenance: Provenance) -> Result<(), CommonError> {
+        if let Some(writer) = &mut self.provenance {
+            writeln!(writer, "{}", provenance.to_json())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Buffer, Output, Provenance, WriteOutput};
+    use std::io::BufWriter;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_write_output() {
+        let mut bytes = Vec::new();
+        {
+            let mut output = WriteOutput::new(BufWriter::new(&mut bytes));
+            output.write_str("Hello, ").unwrap();
+            output.write_str("Québec").unwrap();
+            assert_eq!(output.written(), 14);
+            output.flush().unwrap();
+        }
+        assert_eq!(String::from_utf8(bytes).unwrap(), "Hello, Québec");
+    }
+
+    /// A writer shared with the test, because the output owns its writers.
+    #[derive(Clone,Default)]
+    struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_provenance() {
+        let provenance = SharedWriter::default();
+        let mut output = WriteOutput::new(Vec::new()).with_provenance(provenance.clone());
+        output.write_str("Hello, ").unwrap();
+        let mut buffer = Buffer::default();
+        buffer.write_str("Québec").unwrap();
+        buffer.record(Provenance{output: 0..7, template: 5..40, url: Some(String::from("https://mock")), selector: Some(String::from("h1")), node_index: Some(0),
+            timestamp: UNIX_EPOCH + Duration::from_millis(1500)}).unwrap();
+        buffer.write_to(&mut output).unwrap();
+        output.flush().unwrap();
+        assert_eq!(String::from_utf8(provenance.0.lock().unwrap().clone()).unwrap(),
+            "{\"node_index\":0,\"output\":{\"end\":14,\"start\":7},\"selector\":\"h1\",\"template\":{\"end\":40,\"start\":5},\"timestamp\":1.5,\"url\":\"https://mock\"}\n");
+    }
+}
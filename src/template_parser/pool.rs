@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// The threads that render the iterations of the loops. The pool is shared by all the loops of a
+/// template, so that the nested loops do not multiply the threads.
+#[derive(Debug)]
+pub struct WorkerPool {
+    /// The threads that can be spawned besides the ones which are rendering.
+    idle: AtomicUsize
+}
+
+/// The items of `for_each_ordered` that are waiting, running or done.
+struct Progress<I, R> {
+    items: I,
+    /// The index of the next item to run.
+    next_item: usize,
+    /// The results that are done, but not consumed because an earlier result is not done yet.
+    done: BTreeMap<usize, R>,
+    /// The index of the next result to consume.
+    next_result: usize,
+    /// Whether the consumer failed, or a task panicked, so that no more items are run.
+    stopped: bool
+}
+
+/// Stops the other threads if the task panics, so that the consumer does not wait for its result.
+struct StopOnPanic<'a, I, R> {
+    progress: &'a Mutex<Progress<I, R>>,
+    changed: &'a Condvar
+}
+
+impl<I, R> Drop for StopOnPanic<'_, I, R> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.progress.lock().unwrap_or_else(|e| e.into_inner()).stopped = true;
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// The idle workers taken by `for_each_ordered`, which are returned to the pool when it is dropped,
+/// even if a task panics.
+struct TakenWorkers<'a> {
+    idle: &'a AtomicUsize,
+    count: usize
+}
+
+impl Drop for TakenWorkers<'_> {
+    fn drop(&mut self) {
+        self.idle.fetch_add(self.count, Ordering::SeqCst);
+    }
+}
+
+impl WorkerPool {
+    /// Creates the pool which renders with at most `concurrency` threads, including the main thread.
+    pub fn new(concurrency: usize) -> Self {
+        WorkerPool{idle: AtomicUsize::new(concurrency.max(1) - 1)}
+    }
+
+    /// Runs the task for each of the items on the current thread and the idle workers, and returns
+    /// the results in the order of the items. It runs on the current thread only if no worker is idle.
+    pub fn map<T: Send, R: Send>(&self, items: Vec<T>, task: impl Fn(T) -> R + Sync) -> Vec<R> {
+        let mut results = Vec::with_capacity(items.len());
+        let consumed: Result<(), std::convert::Infallible> = self.for_each_ordered(items, task, |result| {
+            results.push(result);
+            Ok(())
+        });
+        match consumed {
+            Ok(()) => results,
+            Err(never) => match never {}
+        }
+    }
+
+    /// Runs the task for each of the items on the idle workers, and passes each result to `consume`
+    /// on the current thread as soon as all the earlier results are consumed. The current thread
+    /// only consumes, so a worker is spawned in its place, and it runs the items by itself if no
+    /// worker is idle. The workers run at most a few items ahead of the consumed ones, so that the
+    /// results waiting for an earlier one do not pile up. The first error of `consume` stops the
+    /// remaining items and is returned.
+    pub fn for_each_ordered<T: Send, R: Send, E>(&self, items: Vec<T>, task: impl Fn(T) -> R + Sync, mut consume: impl FnMut(R) -> Result<(), E>) -> Result<(), E> {
+        let taken = self.take(items.len().saturating_sub(1));
+        let workers = taken.count;
+        if workers == 0 {
+            for item in items {
+                consume(task(item))?;
+            }
+            return Ok(());
+        }
+        let length = items.len();
+        let window = 2 * (workers + 1);
+        let progress = Mutex::new(Progress{items: items.into_iter(), next_item: 0, done: BTreeMap::new(), next_result: 0, stopped: false});
+        let changed = Condvar::new();
+        let lock = || progress.lock().unwrap_or_else(|e| e.into_inner());
+        // Takes the next item if it is in the window.
+        let next_item = |state: &mut MutexGuard<Progress<std::vec::IntoIter<T>, R>>| {
+            if state.stopped || state.next_item >= state.next_result + window {
+                return None;
+            }
+            let item = state.items.next()?;
+            state.next_item += 1;
+            Some((state.next_item - 1, item))
+        };
+        let run = |index: usize, item: T| {
+            let guard = StopOnPanic{progress: &progress, changed: &changed};
+            let result = task(item);
+            lock().done.insert(index, result);
+            changed.notify_all();
+            drop(guard);
+        };
+        let result = std::thread::scope(|scope| {
+            for _ in 0..workers + 1 {
+                scope.spawn(|| {
+                    let mut state = lock();
+                    loop {
+                        if state.stopped || state.next_item == length {
+                            return;
+                        }
+                        match next_item(&mut state) {
+                            Some((index, item)) => {
+                                drop(state);
+                                run(index, item);
+                                state = lock();
+                            },
+                            None => state = changed.wait(state).unwrap_or_else(|e| e.into_inner())
+                        }
+                    }
+                });
+            }
+            let mut state = lock();
+            while state.next_result < length && !state.stopped {
+                let next_result = state.next_result;
+                if let Some(result) = state.done.remove(&next_result) {
+                    state.next_result += 1;
+                    drop(state);
+                    changed.notify_all();
+                    let consumed = consume(result);
+                    state = lock();
+                    if consumed.is_err() {
+                        state.stopped = true;
+                        changed.notify_all();
+                        return consumed;
+                    }
+                } else {
+                    state = changed.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+            }
+            Ok(())
+        });
+        drop(taken);
+        result
+    }
+
+    /// Takes at most `wanted` idle workers.
+    fn take(&self, wanted: usize) -> TakenWorkers<'_> {
+        let mut count = 0;
+        let _ = self.idle.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |idle| {
+            count = idle.min(wanted);
+            Some(idle - count)
+        });
+        TakenWorkers{idle: &self.idle, count}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WorkerPool;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_map() {
+        let pool = WorkerPool::new(3);
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let results = pool.map((0..10).collect(), |x: u64| {
+            let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now_running, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(10 - x));
+            running.fetch_sub(1, Ordering::SeqCst);
+            x * 2
+        });
+        assert_eq!(results, (0..10).map(|x| x * 2).collect::<Vec<_>>());
+        assert!(max_running.load(Ordering::SeqCst) <= 3);
+        assert_eq!(pool.take(5).count, 2);
+    }
+
+    #[test]
+    fn test_for_each_ordered() {
+        use std::sync::mpsc;
+        // The last item waits until the first result is consumed, which it never is if the results
+        // are only consumed after all the items are done.
+        let pool = WorkerPool::new(3);
+        let (first_consumed, wait_first) = mpsc::channel::<()>();
+        let wait_first = std::sync::Mutex::new(wait_first);
+        let mut consumed = Vec::new();
+        let result: Result<(), String> = pool.for_each_ordered(vec![0, 1, 2], |x| {
+            if x == 2 {
+                let streamed = wait_first.lock().unwrap().recv_timeout(Duration::from_secs(5)).is_ok();
+                (x, streamed)
+            } else {
+                (x, true)
+            }
+        }, |(x, streamed)| {
+            if x == 0 {
+                first_consumed.send(()).unwrap();
+            }
+            consumed.push((x, streamed));
+            Ok(())
+        });
+        assert!(result.is_ok());
+        assert_eq!(consumed, vec![(0, true), (1, true), (2, true)]);
+
+        // The first error stops the remaining items.
+        let ran = AtomicUsize::new(0);
+        let result = pool.for_each_ordered((0..100).collect(), |x: usize| {
+            ran.fetch_add(1, Ordering::SeqCst);
+            x
+        }, |x| if x == 1 { Err(format!("failed at {}", x)) } else { Ok(()) });
+        assert_eq!(result, Err(String::from("failed at 1")));
+        assert!(ran.load(Ordering::SeqCst) < 100);
+        assert_eq!(pool.take(5).count, 2);
+    }
+
+    #[test]
+    fn test_map_panic() {
+        let pool = WorkerPool::new(3);
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pool.map(vec![0, 1, 2], |x| if x == 1 { panic!("failed at {}", x) } else { x })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(pool.take(5).count, 2);
+    }
+
+    #[test]
+    fn test_nested_map() {
+        let pool = WorkerPool::new(2);
+        let results = pool.map(vec![1, 2], |x| pool.map(vec![x, x * 10], |y| y + 1));
+        assert_eq!(results, vec![vec![2, 11], vec![3, 21]]);
+        assert_eq!(WorkerPool::new(1).map(vec![1, 2], |x| x), vec![1, 2]);
+    }
+}
@@ -0,0 +1,234 @@
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// What an untrusted template is allowed to do. Nothing is limited by default.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct SandboxPolicy {
+    /// The hosts that the documents can be fetched from, e.g. `statcan.gc.ca` or `*.statcan.gc.ca`.
+    /// Any host is allowed if it is empty.
+    pub allowed_hosts: Vec<String>,
+    /// Whether to refuse the hosts at private, loopback or link-local addresses.
+    pub deny_private_ips: bool,
+    pub max_fetches: Option<usize>,
+    /// The maximum number of the iterations of all the loops.
+    pub max_loop_iterations: Option<usize>,
+    /// The maximum number of the loops nested in each other.
+    pub max_nesting_depth: Option<usize>,
+    pub max_output_bytes: Option<usize>,
+    /// How long the render can take.
+    pub time_budget: Option<Duration>
+}
+
+/// A violation of the sandbox policy.
+#[derive(Debug,Clone,PartialEq)]
+pub enum SandboxError {
+    HostNotAllowed(String),
+    PrivateAddress(String, IpAddr),
+    TooManyFetches(usize),
+    TooManyLoopIterations(usize),
+    TooDeeplyNested(usize),
+    OutputTooLarge(usize),
+    TimeBudgetExceeded(Duration)
+}
+
+impl std::error::Error for SandboxError {}
+
+impl std::fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[SANDBOX ERROR] ")?;
+        match self {
+            SandboxError::HostNotAllowed(url) => write!(f, "the host of the url is not allowed: {}", url),
+            SandboxError::PrivateAddress(url, ip) => write!(f, "the host of the url is at the private address {}: {}", ip, url),
+            SandboxError::TooManyFetches(max) => write!(f, "the template fetches more than {} documents", max),
+            SandboxError::TooManyLoopIterations(max) => write!(f, "the loops of the template iterate more than {} times", max),
+            SandboxError::TooDeeplyNested(max) => write!(f, "the loops of the template are nested deeper than {}", max),
+            SandboxError::OutputTooLarge(max) => write!(f, "the output is larger than {} bytes", max),
+            SandboxError::TimeBudgetExceeded(budget) => write!(f, "the render takes longer than {:?}", budget)
+        }
+    }
+}
+
+/// The sandbox of a render, which enforces the policy and counts what the render has used.
+#[derive(Debug)]
+pub struct Sandbox {
+    policy: SandboxPolicy,
+    fetches: AtomicUsize,
+    loop_iterations: AtomicUsize,
+    started: Mutex<Instant>
+}
+
+impl Sandbox {
+    pub fn new(policy: SandboxPolicy) -> Self {
+        Sandbox{policy, fetches: AtomicUsize::new(0), loop_iterations: AtomicUsize::new(0), started: Mutex::new(Instant::now())}
+    }
+
+    /// Starts the budget of a new render.
+    pub fn start(&self) {
+        self.fetches.store(0, Ordering::SeqCst);
+        self.loop_iterations.store(0, Ordering::SeqCst);
+        *self.started.lock().unwrap() = Instant::now();
+    }
+
+    /// Checks whether the url can be fetched, without counting it as a fetch. It is also used for
+    /// the redirects. The addresses of the host are checked again by `check_address` when the
+    /// request connects, because the host may resolve to another address by then.
+    pub fn check_url(&self, url: &url::Url) -> Result<(), SandboxError> {
+        let host = url.host_str().unwrap_or("").trim_start_matches('[').trim_end_matches(']').to_lowercase();
+        if !self.policy.allowed_hosts.is_empty() && !self.policy.allowed_hosts.iter().any(|pattern| host_matches(&pattern.to_lowercase(), &host)) {
+            return Err(SandboxError::HostNotAllowed(url.to_string()));
+        }
+        if self.policy.deny_private_ips {
+            // A host that is not resolved is left to fail the request.
+            let addresses: Vec<IpAddr> = match host.parse::<IpAddr>() {
+                Ok(ip) => vec![ip],
+                Err(_) => (host.as_str(), url.port_or_known_default().unwrap_or(80)).to_socket_addrs()
+                    .map(|addresses| addresses.map(|x| x.ip()).collect())
+                    .unwrap_or_default()
+            };
+            for ip in addresses {
+                self.check_address(url, ip)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks an address that the host of the url resolves to.
+    pub fn check_address(&self, url: &url::Url, ip: IpAddr) -> Result<(), SandboxError> {
+        if self.policy.deny_private_ips && is_private(&ip) {
+            return Err(SandboxError::PrivateAddress(url.to_string(), ip));
+        }
+        Ok(())
+    }
+
+    /// Whether the number of the fetches is limited.
+    pub fn limits_fetches(&self) -> bool {
+        self.policy.max_fetches.is_some()
+    }
+
+    /// Checks the url and the time, without counting it as a fetch.
+    pub fn check_request(&self, url: &str) -> Result<(), SandboxError> {
+        self.check_time()?;
+        if let Ok(parsed_url) = url::Url::parse(url) {
+            self.check_url(&parsed_url)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the url, the time and the number of the fetches before a document is fetched.
+    pub fn check_fetch(&self, url: &str) -> Result<(), SandboxError> {
+        self.check_request(url)?;
+        let fetches = self.fetches.fetch_add(1, Ordering::SeqCst) + 1;
+        match self.policy.max_fetches {
+            Some(max) if fetches > max => Err(SandboxError::TooManyFetches(max)),
+            _ => Ok(())
+        }
+    }
+
+    pub fn check_loop_iterations(&self, iterations: usize) -> Result<(), SandboxError> {
+        let total = self.loop_iterations.fetch_add(iterations, Ordering::SeqCst) + iterations;
+        match self.policy.max_loop_iterations {
+            Some(max) if total > max => Err(SandboxError::TooManyLoopIterations(max)),
+            _ => Ok(())
+        }
+    }
+
+    pub fn check_nesting_depth(&self, depth: usize) -> Result<(), SandboxError> {
+        match self.policy.max_nesting_depth {
+            Some(max) if depth > max => Err(SandboxError::TooDeeplyNested(max)),
+            _ => Ok(())
+        }
+    }
+
+    pub fn check_output(&self, output_bytes: usize) -> Result<(), SandboxError> {
+        match self.policy.max_output_bytes {
+            Some(max) if output_bytes > max => Err(SandboxError::OutputTooLarge(max)),
+            _ => Ok(())
+        }
+    }
+
+    /// When the time budget of the render ends, if there is one.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.policy.time_budget.map(|budget| *self.started.lock().unwrap() + budget)
+    }
+
+    pub fn check_time(&self) -> Result<(), SandboxError> {
+        match self.policy.time_budget {
+            Some(budget) if self.started.lock().unwrap().elapsed() > budget => Err(SandboxError::TimeBudgetExceeded(budget)),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Matches the host with the pattern, where `*.example.com` matches the subdomains of `example.com`.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.len() > domain.len() && host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'),
+        None => pattern == host
+    }
+}
+
+/// Whether the address is not public: private, loopback, link-local, shared, unspecified or broadcast.
+fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+            || (ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ipv4) => is_private(&IpAddr::V4(ipv4)),
+            None => ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sandbox, SandboxError, SandboxPolicy};
+    use std::time::Duration;
+
+    fn check(sandbox: &Sandbox, url: &str) -> Result<(), SandboxError> {
+        sandbox.check_url(&url::Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_check_url() {
+        let sandbox = Sandbox::new(SandboxPolicy{allowed_hosts: vec![String::from("statcan.gc.ca"), String::from("*.Canada.ca")], ..Default::default()});
+        assert!(check(&sandbox, "https://statcan.gc.ca/en").is_ok());
+        assert!(check(&sandbox, "https://www.canada.ca/en").is_ok());
+        assert_eq!(check(&sandbox, "https://canada.ca/en"), Err(SandboxError::HostNotAllowed(String::from("https://canada.ca/en"))));
+        assert!(check(&sandbox, "https://www.statcan.gc.ca").is_err());
+        assert!(check(&sandbox, "https://evilcanada.ca").is_err());
+
+        let sandbox = Sandbox::new(SandboxPolicy{deny_private_ips: true, ..Default::default()});
+        assert_eq!(check(&sandbox, "http://127.0.0.1:8080/").unwrap_err().to_string(), "[SANDBOX ERROR] the host of the url is at the private address 127.0.0.1: http://127.0.0.1:8080/");
+        for url in ["http://10.1.2.3", "http://192.168.0.1", "http://169.254.169.254/latest", "http://100.64.0.1", "http://[::1]/", "http://[fd00::1]/", "http://[::ffff:10.0.0.1]/", "http://localhost/"] {
+            assert!(matches!(check(&sandbox, url), Err(SandboxError::PrivateAddress(..))), "{}", url);
+        }
+        assert!(check(&sandbox, "http://8.8.8.8").is_ok());
+        let url = url::Url::parse("http://rebinding.example").unwrap();
+        assert!(sandbox.check_address(&url, "8.8.8.8".parse().unwrap()).is_ok());
+        assert_eq!(sandbox.check_address(&url, "169.254.169.254".parse().unwrap()), Err(SandboxError::PrivateAddress(String::from("http://rebinding.example/"), "169.254.169.254".parse().unwrap())));
+        assert!(Sandbox::new(SandboxPolicy::default()).check_address(&url, "127.0.0.1".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_budget() {
+        let sandbox = Sandbox::new(SandboxPolicy{max_fetches: Some(2), max_loop_iterations: Some(3), max_nesting_depth: Some(1), max_output_bytes: Some(10), ..Default::default()});
+        assert!(sandbox.check_fetch("https://mock/1").is_ok());
+        assert!(sandbox.check_fetch("https://mock/2").is_ok());
+        assert_eq!(sandbox.check_fetch("https://mock/3"), Err(SandboxError::TooManyFetches(2)));
+        assert!(sandbox.check_loop_iterations(3).is_ok());
+        assert_eq!(sandbox.check_loop_iterations(1), Err(SandboxError::TooManyLoopIterations(3)));
+        assert_eq!(sandbox.check_nesting_depth(2), Err(SandboxError::TooDeeplyNested(1)));
+        assert_eq!(sandbox.check_output(11), Err(SandboxError::OutputTooLarge(10)));
+        sandbox.start();
+        assert!(sandbox.check_fetch("https://mock/1").is_ok());
+
+        let sandbox = Sandbox::new(SandboxPolicy{time_budget: Some(Duration::from_millis(10)), ..Default::default()});
+        assert!(sandbox.check_time().is_ok());
+        assert!(sandbox.deadline().unwrap() > std::time::Instant::now());
+        assert_eq!(Sandbox::new(SandboxPolicy::default()).deadline(), None);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(sandbox.check_fetch("https://mock"), Err(SandboxError::TimeBudgetExceeded(Duration::from_millis(10))));
+    }
+}
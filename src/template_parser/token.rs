@@ -1,4 +1,74 @@
-use super::{DocBuilder, TemplateNode, StrTemplateNode, CssTemplateNode, LoopTemplateNode, EndTemplateNode, VarTemplateNode};
+use super::{DocBuilder, DocSource, TemplateError, TemplateNode, StrTemplateNode, CssTemplateNode, RegexTemplateNode, MetaTemplateNode, JsonLdTemplateNode, LoopTemplateNode, SelectorKind, LoopRefinement, LoopOrder, OnError, OnErrorTemplateNode, EndTemplateNode, ElseTemplateNode, VarTemplateNode};
+use regex::Regex;
+use crate::fetch::RequestOptions;
+use crate::fetch::charset;
+use crate::fetch::limits::DocFormat;
+use std::ops::Range;
+use std::time::Duration;
+
+/// The attributes of the tokens which fetch a document, which are read by `doc_source` and `on_error`.
+const DOC_ATTRIBUTES: &[&str] = &["doc", "doc-var", "base-doc", "method", "user-agent", "json-body", "timeout", "retries", "retry-delay", "encoding", "on-error"];
+/// The prefixes of the attributes of the request, e.g. `header-accept`.
+const DOC_PREFIXES: &[&str] = &["header-", "form-"];
+
+/// The attributes of the keyword besides `DOC_ATTRIBUTES`, and whether it fetches a document, or
+/// `None` for an unknown keyword.
+fn own_attributes(keyword: &str) -> Option<(&'static [&'static str], bool)> {
+    match keyword {
+        "css" => Some((&["selector", "default", "node-property", "absolute-url"], true)),
+        "regex" => Some((&["pattern", "group", "default"], true)),
+        "meta" => Some((&["name", "type", "default"], true)),
+        "jsonld" => Some((&["path", "type", "default"], true)),
+        "loop" => Some((&["var", "selector", "pattern", "node-property", "absolute-url", "separator", "where", "unique", "sort", "reverse", "offset", "step", "limit", "required"], true)),
+        "table" => Some((&["var", "selector", "node-property", "absolute-url", "separator", "where", "unique", "sort", "reverse", "offset", "step", "limit", "required"], true)),
+        "var" | "else" | "end" => Some((&[], false)),
+        _ => None
+    }
+}
+
+/// A piece of the template, with its byte range in the template.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Piece {
+    Text(Range<usize>),
+    /// A token between `{{` and `}}`, and its keyword and attributes.
+    Token(Range<usize>, Vec<String>),
+    /// A `{{` without its `}}`, which ends the template.
+    Unclosed(usize)
+}
+
+/// Splits the template into the texts and the tokens without building the nodes, e.g. to check
+/// the template.
+pub fn scan(template: &str) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut cursor = 0;
+    while let Some(start) = template[cursor..].find("{{").map(|x| x + cursor) {
+        if start > cursor {
+            pieces.push(Piece::Text(cursor..start));
+        }
+        match template[start + 2..].find("}}").map(|x| x + start + 2) {
+            Some(end) => {
+                pieces.push(Piece::Token(start..end + 2, TokenParser::tokenize(&template[start + 2..end])));
+                cursor = end + 2;
+            },
+            None => {
+                pieces.push(Piece::Unclosed(start));
+                return pieces;
+            }
+        }
+    }
+    if cursor < template.len() {
+        pieces.push(Piece::Text(cursor..template.len()));
+    }
+    pieces
+}
+
+/// The line and the column(in characters) of the byte position in the template, both 1-based.
+pub fn line_column(template: &str, position: usize) -> (usize, usize) {
+    let before = &template[..position];
+    let line = before.matches('\n').count() + 1;
+    let column = before[before.rfind('\n').map(|x| x + 1).unwrap_or(0)..].chars().count() + 1;
+    (line, column)
+}
 
 pub struct TokenParser<'a> {
     template: String,
@@ -7,28 +77,34 @@ pub struct TokenParser<'a> {
 }
 
 impl<'a> Iterator for TokenParser<'a> {
-    type Item = Box<dyn TemplateNode>;
+    /// The nodes of the template, or the error of the first token that fails to parse, which ends
+    /// the iteration.
+    type Item = Result<Box<dyn TemplateNode>, TemplateError>;
     fn next(&mut self) -> Option<Self::Item> {
         match self.next_token() {
-            Some(token_pos) => {
+            Ok(Some(token_pos)) => {
                 if token_pos.0 > self.cursor {
-                    let result: Box<dyn TemplateNode> = Box::new(StrTemplateNode{text: String::from(&self.template[self.cursor..token_pos.0])});
+                    let result: Box<dyn TemplateNode> = Box::new(StrTemplateNode{text: String::from(&self.template[self.cursor..token_pos.0]), span: self.cursor..token_pos.0});
                     self.cursor = token_pos.0;
-                    Some(result)
+                    Some(Ok(result))
                 } else {
-                    let result: Box<dyn TemplateNode> = Self::token_to_template_node(&self.template[token_pos.0+2..token_pos.1], self.doc_builder);
-                    self.cursor = token_pos.1 + 2;
+                    let result = Self::token_to_template_node(&self.template[token_pos.0+2..token_pos.1], token_pos.0..token_pos.1+2, self.doc_builder);
+                    self.cursor = if result.is_ok() { token_pos.1 + 2 } else { self.template.len() };
                     Some(result)
                 }
             },
-            None => {
+            Ok(None) => {
                 if self.cursor < self.template.len() {
-                    let result: Box<dyn TemplateNode> = Box::new(StrTemplateNode{text: String::from(&self.template[self.cursor..])});
+                    let result: Box<dyn TemplateNode> = Box::new(StrTemplateNode{text: String::from(&self.template[self.cursor..]), span: self.cursor..self.template.len()});
                     self.cursor = self.template.len();
-                    Some(result)
+                    Some(Ok(result))
                 } else {
                     None
                 }
+            },
+            Err(error) => {
+                self.cursor = self.template.len();
+                Some(Err(error))
             }
         }
     }
@@ -38,73 +114,237 @@ impl<'a> TokenParser<'a> { pub fn new(template: &str, doc_builder: &'a DocBuilde
         TokenParser{template: String::from(template), cursor: 0, doc_builder}
     }
 
-    fn next_token(&self) -> Option<(usize,usize)> {
+    fn next_token(&self) -> Result<Option<(usize,usize)>, TemplateError> {
         if let Some(start_pos) = self.template[self.cursor..].find("{{") {
             let cur_pos = self.cursor + start_pos + 2;
             if let Some(end_pos) = self.template[cur_pos..].find("}}") {
                 let token_pos = (start_pos+self.cursor, end_pos+cur_pos);
-                Some(token_pos)
+                Ok(Some(token_pos))
             } else {
-                panic!("unable to find matched `}}` token for the token at: {}", start_pos+self.cursor);
+                Err(TemplateError::new(format!("unable to find matched `}}}}` token for the token at: {}", start_pos+self.cursor)))
             }
         } else {
-            None
+            Ok(None)
         }
     }
 
-    fn token_to_template_node(token: &str, doc_builder: &'a DocBuilder) -> Box<dyn TemplateNode> {
+    fn token_to_template_node(token: &str, span: Range<usize>, doc_builder: &'a DocBuilder) -> Result<Box<dyn TemplateNode>, TemplateError> {
         let tokens = Self::tokenize(token);
+        if tokens.is_empty() {
+            return Err(TemplateError::new(format!("missing the keyword of the token at: {}", span.start)));
+        }
+        if let Some(key) = Self::unknown_attributes(&tokens).first() {
+            return Err(TemplateError::new(format!("unknown attribute '{}' of the {} token", key, tokens[0])));
+        }
         if tokens[0] == "css" {
             let selector = Self::tokenized_value_by_key(&tokens, "selector");
             let default = Self::tokenized_value_by_key(&tokens, "default");
             let node_property = Self::tokenized_value_by_key(&tokens, "node-property");
             if selector.is_none() {
-                panic!("unable to find `selector` in the css token: {}", token);
+                return Err(TemplateError::new(format!("unable to find `selector` in the css token: {}", token)));
             }
-            let doc = Self::tokenized_value_by_key(&tokens, "doc");
-            let doc_var = Self::tokenized_value_by_key(&tokens, "doc-var");
-            let base_doc = Self::tokenized_value_by_key(&tokens, "base-doc");
-            Box::new(CssTemplateNode{css_selector: selector.unwrap(), doc, doc_var, base_doc, default_value: default, node_property, doc_builder: doc_builder.clone()})
-        } else if tokens[0] == "loop" {
-            let loop_var = Self::tokenized_value_by_key(&tokens, "var").expect("missing 'var' for the loop");
-            let selector = Self::tokenized_value_by_key(&tokens, "selector");
-            if selector.is_none() {
-                panic!("unable to find `selector` in the css token: {}", token);
+            let source = Self::doc_source(&tokens, DocFormat::Html)?;
+            let absolute_url = Self::tokenized_flag(&tokens, "absolute-url");
+            Self::with_on_error(&tokens, Box::new(CssTemplateNode{span, css_selector: selector.unwrap(), source, default_value: default, node_property, absolute_url, doc_builder: doc_builder.clone()}), doc_builder)
+        } else if tokens[0] == "regex" {
+            let pattern = Self::tokenized_value_by_key(&tokens, "pattern");
+            if pattern.is_none() {
+                return Err(TemplateError::new(format!("unable to find `pattern` in the regex token: {}", token)));
             }
-            let selector = selector.unwrap();
+            let group = Self::tokenized_value_by_key(&tokens, "group");
+            let default = Self::tokenized_value_by_key(&tokens, "default");
+            let source = Self::doc_source(&tokens, DocFormat::Text)?;
+            Self::with_on_error(&tokens, Box::new(RegexTemplateNode{span, pattern: pattern.unwrap(), group, default_value: default, source, doc_builder: doc_builder.clone()}), doc_builder)
+        } else if tokens[0] == "meta" {
+            let name = Self::tokenized_value_by_key(&tokens, "name");
+            if name.is_none() {
+                return Err(TemplateError::new(format!("unable to find `name` in the meta token: {}", token)));
+            }
+            let item_type = Self::tokenized_value_by_key(&tokens, "type");
+            let default = Self::tokenized_value_by_key(&tokens, "default");
+            let source = Self::doc_source(&tokens, DocFormat::Html)?;
+            Self::with_on_error(&tokens, Box::new(MetaTemplateNode{span, name: name.unwrap(), item_type, default_value: default, source, doc_builder: doc_builder.clone()}), doc_builder)
+        } else if tokens[0] == "jsonld" {
+            let path = Self::tokenized_value_by_key(&tokens, "path");
+            if path.is_none() {
+                return Err(TemplateError::new(format!("unable to find `path` in the jsonld token: {}", token)));
+            }
+            let item_type = Self::tokenized_value_by_key(&tokens, "type");
+            let default = Self::tokenized_value_by_key(&tokens, "default");
+            let source = Self::doc_source(&tokens, DocFormat::Html)?;
+            Self::with_on_error(&tokens, Box::new(JsonLdTemplateNode{span, path: path.unwrap(), item_type, default_value: default, source, doc_builder: doc_builder.clone()}), doc_builder)
+        } else if tokens[0] == "loop" || tokens[0] == "table" {
+            let loop_var = Self::tokenized_value_by_key(&tokens, "var").ok_or_else(|| TemplateError::new(format!("missing 'var' for the {}", tokens[0])))?;
+            let (selector, selector_kind) = match (Self::tokenized_value_by_key(&tokens, "selector"), Self::tokenized_value_by_key(&tokens, "pattern")) {
+                (Some(selector), None) if tokens[0] == "table" => (selector, SelectorKind::Table),
+                (Some(selector), None) => (selector, SelectorKind::Css),
+                (None, Some(pattern)) if tokens[0] == "loop" => (pattern, SelectorKind::Regex),
+                (Some(_), Some(_)) => return Err(TemplateError::new(format!("only one of `selector` and `pattern` can be used in the loop token: {}", token))),
+                _ => return Err(TemplateError::new(format!("unable to find `selector` in the {} token: {}", tokens[0], token)))
+            };
             let node_property = Self::tokenized_value_by_key(&tokens, "node-property");
-            let doc = Self::tokenized_value_by_key(&tokens, "doc");
-            let doc_var = Self::tokenized_value_by_key(&tokens, "doc-var");
-            let base_doc = Self::tokenized_value_by_key(&tokens, "base-doc");
+            let source = Self::doc_source(&tokens, if selector_kind == SelectorKind::Regex { DocFormat::Text } else { DocFormat::Html })?;
+            let separator = Self::tokenized_value_by_key(&tokens, "separator");
+            let refinement = Self::loop_refinement(&tokens)?;
+            let required = Self::tokenized_flag(&tokens, "required");
+            let absolute_url = Self::tokenized_flag(&tokens, "absolute-url");
+            let on_error = Self::on_error(&tokens)?;
 
-            Box::new(LoopTemplateNode{var_name: loop_var, css_selector: selector, node_property, doc, doc_var, base_doc, children: Vec::new(), doc_builder: doc_builder.clone()})
+            Ok(Box::new(LoopTemplateNode{span, var_name: loop_var, selector, selector_kind, node_property, absolute_url, source, separator, refinement, required, on_error, children: Vec::new(), else_children: None, doc_builder: doc_builder.clone()}))
         } else if tokens[0] == "var" {
-            let var_name = tokens[1].clone(); 
-            Box::new(VarTemplateNode{var_name})
+            let var_name = tokens.get(1).cloned().ok_or_else(|| TemplateError::new(format!("missing the name of the var token: {}", token)))?;
+            Ok(Box::new(VarTemplateNode{var_name, span}))
+        } else if tokens[0] == "else" {
+            Ok(Box::new(ElseTemplateNode{span}))
         } else if tokens[0] == "end" {
-            Box::new(EndTemplateNode{})
+            Ok(Box::new(EndTemplateNode{span}))
         } else {
-            panic!("unknown keyword: {}", tokens[0])
+            Err(TemplateError::new(format!("unknown keyword: {}", tokens[0])))
+        }
+    }
+
+    /// The problems of the token that fail to parse it, as many of them as are found, e.g. for
+    /// `check` to report all of them at once.
+    pub fn problems(token: &str, doc_builder: &'a DocBuilder) -> Vec<String> {
+        let tokens = Self::tokenize(token);
+        let keyword = match own_attributes(tokens.first().map(|x| x.as_str()).unwrap_or("")) {
+            Some(_) => tokens[0].as_str(),
+            None => return Self::token_to_template_node(token, 0..0, doc_builder).err().map(|x| x.message().to_owned()).into_iter().collect()
+        };
+        let mut problems: Vec<String> = Self::unknown_attributes(&tokens).into_iter().map(|key| format!("unknown attribute '{}' of the {} token", key, keyword)).collect();
+        let mut errors = vec![Self::token_to_template_node(token, 0..0, doc_builder).err()];
+        if own_attributes(keyword).map(|(_, fetches_doc)| fetches_doc).unwrap_or(false) {
+            errors.push(Self::doc_source(&tokens, DocFormat::Html).err());
+            errors.push(Self::on_error(&tokens).err());
+        }
+        if keyword == "loop" || keyword == "table" {
+            errors.push(Self::loop_refinement(&tokens).err());
+        }
+        for error in errors.into_iter().flatten() {
+            if !problems.iter().any(|x| x == error.message()) {
+                problems.push(error.message().to_owned());
+            }
+        }
+        problems
+    }
+
+    /// The attributes that the keyword does not take, e.g. a misspelled `selectr`.
+    fn unknown_attributes(tokens: &[String]) -> Vec<String> {
+        let (own, fetches_doc) = match tokens.first().and_then(|x| own_attributes(x)) {
+            Some(attributes) => attributes,
+            None => return Vec::new()
+        };
+        // The name of a variable is not an attribute.
+        let mut idx = if tokens[0] == "var" { 2 } else { 1 };
+        let mut unknown = Vec::new();
+        while idx < tokens.len() {
+            let key = &tokens[idx];
+            let is_doc_attribute = fetches_doc && (DOC_ATTRIBUTES.contains(&key.as_str()) || DOC_PREFIXES.iter().any(|prefix| key.len() > prefix.len() && key.starts_with(prefix)));
+            if !own.contains(&key.as_str()) && !is_doc_attribute {
+                unknown.push(key.clone());
+            }
+            idx += if tokens.get(idx + 1).map(|x| x == "=").unwrap_or(false) { 3 } else { 1 };
+        }
+        unknown
+    }
+
+    fn on_error(tokens: &[String]) -> Result<Option<OnError>, TemplateError> {
+        Self::tokenized_value_by_key(tokens, "on-error").map(|on_error| on_error.parse().map_err(TemplateError::new)).transpose()
+    }
+
+    /// Wraps the token by its own `on-error` if it has one.
+    fn with_on_error(tokens: &[String], node: Box<dyn TemplateNode>, doc_builder: &DocBuilder) -> Result<Box<dyn TemplateNode>, TemplateError> {
+        Ok(match Self::on_error(tokens)? {
+            Some(on_error) => Box::new(OnErrorTemplateNode{on_error, node, doc_builder: doc_builder.clone()}),
+            None => node
+        })
+    }
+
+    fn doc_source(tokens: &[String], format: DocFormat) -> Result<DocSource, TemplateError> {
+        let mut request = RequestOptions{
+            method: Self::tokenized_value_by_key(tokens, "method"),
+            headers: Self::tokenized_values_by_prefix(tokens, "header-"),
+            form: Self::tokenized_values_by_prefix(tokens, "form-"),
+            json_body: Self::tokenized_value_by_key(tokens, "json-body"),
+            timeout: Self::tokenized_seconds(tokens, "timeout")?,
+            retries: Self::tokenized_value_by_key(tokens, "retries")
+                .map(|value| value.parse::<u32>().map_err(|_| TemplateError::new(format!("'retries' must be a non-negative number: {}", value))))
+                .transpose()?,
+            retry_delay: Self::tokenized_seconds(tokens, "retry-delay")?,
+            encoding: Self::tokenized_value_by_key(tokens, "encoding"),
+            format: Some(format)
+        };
+        if let Some(encoding) = &request.encoding {
+            charset::encoding_for_label(encoding).map_err(|_| TemplateError::new(format!("unknown encoding: {}", encoding)))?;
+        }
+        if let Some(user_agent) = Self::tokenized_value_by_key(tokens, "user-agent") {
+            request.headers.push((String::from("user-agent"), user_agent));
+        }
+        let doc = Self::tokenized_value_by_key(tokens, "doc");
+        let doc_var = Self::tokenized_value_by_key(tokens, "doc-var");
+        if doc.is_none() && doc_var.is_none() {
+            return Err(TemplateError::new(format!("'doc' or 'doc-var' must be used for the {} token", tokens[0])));
         }
+        Ok(DocSource{
+            doc,
+            doc_var,
+            base_doc: Self::tokenized_value_by_key(tokens, "base-doc"),
+            request
+        })
     }
 
-    fn tokenize(token: &str) -> Vec<String> {
+    fn loop_refinement(tokens: &[String]) -> Result<LoopRefinement, TemplateError> {
+        let number = |key: &str| Self::tokenized_value_by_key(tokens, key)
+            .map(|value| value.parse::<usize>().map_err(|_| TemplateError::new(format!("'{}' of the loop must be a non-negative number: {}", key, value))))
+            .transpose();
+        let step = number("step")?;
+        if step == Some(0) {
+            return Err(TemplateError::new(String::from("'step' of the loop must be greater than 0")));
+        }
+        let sort = Self::tokenized_value_by_key(tokens, "sort").map(|order| match order.as_str() {
+            "asc" => Ok(LoopOrder::Asc),
+            "desc" => Ok(LoopOrder::Desc),
+            "natural" => Ok(LoopOrder::Natural),
+            _ => Err(TemplateError::new(format!("unknown 'sort' of the loop: {}, expect 'asc', 'desc' or 'natural'", order)))
+        }).transpose()?;
+        let where_pattern = Self::tokenized_value_by_key(tokens, "where")
+            .map(|pattern| Regex::new(&pattern).map_err(|e| TemplateError::new(format!("invalid 'where' pattern of the loop: {}", e))))
+            .transpose()?;
+        Ok(LoopRefinement{
+            limit: number("limit")?,
+            offset: number("offset")?,
+            step,
+            reverse: Self::tokenized_flag(tokens, "reverse"),
+            sort,
+            unique: Self::tokenized_flag(tokens, "unique"),
+            where_pattern
+        })
+    }
+
+    pub fn tokenize(token: &str) -> Vec<String> {
         let token = token.trim();
-        let mut in_quote = false;
+        // The quote that the current value is quoted by, the other quote is a part of the value.
+        let mut quote: Option<char> = None;
         let mut result = Vec::new();
         let mut buff = String::new();
         let mut is_key = true;
-        let mut chars = token.chars();
-        while let Some(c) = chars.next() {
-            if c == '\'' || c == '"' {
-                in_quote = !in_quote;
+        let chars = token.chars();
+        for c in chars {
+            if quote == Some(c) {
+                quote = None;
+                result.push(buff.clone());
+                buff.clear();
+                continue;
+            }
+            if quote.is_none() && (c == '\'' || c == '"') {
+                quote = Some(c);
                 if !buff.is_empty() {
                     result.push(buff.clone());
                     buff.clear();
                 }
                 continue;
             }
-            if in_quote {
+            if quote.is_some() {
                 buff.push(c);
             } else {
                 if c == '=' || c == '>' || c =='<' {
@@ -141,20 +381,52 @@ impl<'a> TokenParser<'a> { pub fn new(template: &str, doc_builder: &'a DocBuilde
         result
     }
 
-    fn tokenized_value_by_key(tokenized: &Vec<String>, key: &str) -> Option<String> {
-        for (idx, ref t) in tokenized.iter().enumerate() {
-            if *t == key && tokenized[idx+1] == "=" {
-                return Some(tokenized[idx+2].clone());
+    fn tokenized_value_by_key(tokenized: &[String], key: &str) -> Option<String> {
+        for (idx, t) in tokenized.iter().enumerate() {
+            if t == key && tokenized.get(idx+1).map(|x| x == "=").unwrap_or(false) {
+                return tokenized.get(idx+2).cloned();
             } 
         }
         None
     }
+
+    /// The value of the key as a duration in seconds, e.g. `timeout='2.5'`.
+    fn tokenized_seconds(tokenized: &[String], key: &str) -> Result<Option<Duration>, TemplateError> {
+        Self::tokenized_value_by_key(tokenized, key).map(|value| {
+            value.parse::<f64>().ok().and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .ok_or_else(|| TemplateError::new(format!("'{}' must be a non-negative number of seconds: {}", key, value)))
+        }).transpose()
+    }
+
+    /// Returns the keys without the prefix and the values of the keys starting with the prefix,
+    /// e.g. `("accept", "text/html")` for `header-accept='text/html'` with the prefix `header-`.
+    fn tokenized_values_by_prefix(tokenized: &[String], prefix: &str) -> Vec<(String, String)> {
+        let mut result = Vec::new();
+        for (idx, t) in tokenized.iter().enumerate() {
+            if let Some(key) = t.strip_prefix(prefix) {
+                if !key.is_empty() && tokenized.get(idx+1).map(|x| x == "=").unwrap_or(false) {
+                    if let Some(value) = tokenized.get(idx+2) {
+                        result.push((String::from(key), value.clone()));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// A flag is a key without value, e.g. `reverse` in `{{loop ... reverse}}`. A value equal to the
+    /// flag, e.g. `separator='reverse'`, is not the flag.
+    fn tokenized_flag(tokenized: &[String], key: &str) -> bool {
+        tokenized.iter().enumerate().skip(1).any(|(idx, t)| t == key
+            && tokenized[idx - 1] != "="
+            && tokenized.get(idx+1).map(|x| x != "=").unwrap_or(true))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::mock::InternalNode;
-    use super::{DocBuilder, TokenParser};
+    use super::{scan, DocBuilder, Piece, TokenParser};
     use std::collections::HashMap;
 
     #[test]
@@ -164,21 +436,57 @@ mod tests {
         mock_data.insert("div.author", vec![author]);
         let doc_builder = DocBuilder::from(mock_data);
         let mut token_parser = TokenParser::new("The author's name is {{css selector='div.author' doc='https://www.google.com'}}!", &doc_builder);
-        let token = token_parser.next().unwrap();
+        let token = token_parser.next().unwrap().unwrap();
         let mut buff = String::new();
         token.evaluate(None, &mut buff).unwrap();
         assert_eq!(buff, "The author's name is ");
-        let token = token_parser.next().unwrap();
+        let token = token_parser.next().unwrap().unwrap();
         token.evaluate(None, &mut buff).unwrap();
         assert_eq!(buff, "The author's name is James Ma");
-        let token = token_parser.next().unwrap();
+        let token = token_parser.next().unwrap().unwrap();
         token.evaluate(None, &mut buff).unwrap();
         assert_eq!(buff, "The author's name is James Ma!");
     }
+
+    #[test]
+    fn test_token_parser_error() {
+        let doc_builder = DocBuilder::from(HashMap::new());
+        let error = |template: &str| TokenParser::new(template, &doc_builder).find_map(|x| x.err()).map(|x| x.to_string());
+        assert_eq!(error("A{{css selector='p'"), Some(String::from("[ERROR] unable to find matched `}}` token for the token at: 1")));
+        assert_eq!(error("{{css selector='p'}}"), Some(String::from("[ERROR] 'doc' or 'doc-var' must be used for the css token")));
+        assert_eq!(error("{{loop var='x' selector='li' step='0' doc='https://mock'}}"), Some(String::from("[ERROR] 'step' of the loop must be greater than 0")));
+        assert_eq!(error("{{loop var='x' selector='li' sort='up' doc='https://mock'}}"), Some(String::from("[ERROR] unknown 'sort' of the loop: up, expect 'asc', 'desc' or 'natural'")));
+        assert_eq!(error("{{regex pattern='a' retries='-1' doc='https://mock'}}"), Some(String::from("[ERROR] 'retries' must be a non-negative number: -1")));
+        assert!(error("{{css selector='p' on-error='retry' doc='https://mock'}}").is_some());
+        assert_eq!(error("{{var}}"), Some(String::from("[ERROR] missing the name of the var token: var")));
+        assert_eq!(error("{{}}"), Some(String::from("[ERROR] missing the keyword of the token at: 0")));
+        assert_eq!(error("{{print x}}"), Some(String::from("[ERROR] unknown keyword: print")));
+        assert_eq!(error("{{css selectr='p' doc='https://mock'}}"), Some(String::from("[ERROR] unknown attribute 'selectr' of the css token")));
+        assert_eq!(error("{{var x y}}"), Some(String::from("[ERROR] unknown attribute 'y' of the var token")));
+        assert_eq!(error("{{css selector='p' encoding='klingon' doc='https://mock'}}"), Some(String::from("[ERROR] unknown encoding: klingon")));
+        // The iteration ends at the first error.
+        assert_eq!(TokenParser::new("A{{print x}}B{{var x}}", &doc_builder).count(), 2);
+        assert_eq!(error("A{{var x}}B"), None);
+    }
+
+    #[test]
+    fn test_scan() {
+        let pieces = scan("A{{var x}}B{{end}}{{css");
+        assert_eq!(pieces, vec![
+            Piece::Text(0..1),
+            Piece::Token(1..10, vec![String::from("var"), String::from("x")]),
+            Piece::Text(10..11),
+            Piece::Token(11..18, vec![String::from("end")]),
+            Piece::Unclosed(18)
+        ]);
+    }
+
     #[test]
     fn test_tokenize() {
         let tokens = TokenParser::tokenize("css selector='div.author  > p' default =\"N/A\"");
         assert_eq!(tokens, vec!["css", "selector", "=", "div.author  > p", "default", "=", "N/A"]);
+        let tokens = TokenParser::tokenize("css json-body='{\"q\": 1}' selector=\"a[title='it']\" default=''");
+        assert_eq!(tokens, vec!["css", "json-body", "=", "{\"q\": 1}", "selector", "=", "a[title='it']", "default", "=", ""]);
     }
 
     #[test]
@@ -189,4 +497,26 @@ mod tests {
         assert_eq!(TokenParser::tokenized_value_by_key(&tokens, "the-key-does-not-exist"), None);
     }
 
+    #[test]
+    fn test_tokenized_values_by_prefix() {
+        let tokens = TokenParser::tokenize("css selector='p' header-accept='text/html' header-accept-language='fr' form-q='canada' header-=1");
+        assert_eq!(TokenParser::tokenized_values_by_prefix(&tokens, "header-"), vec![(String::from("accept"), String::from("text/html")), (String::from("accept-language"), String::from("fr"))]);
+        assert_eq!(TokenParser::tokenized_values_by_prefix(&tokens, "form-"), vec![(String::from("q"), String::from("canada"))]);
+    }
+
+    #[test]
+    fn test_tokenized_flag() {
+        let tokens = TokenParser::tokenize("loop selector='li' reverse unique=1");
+        assert!(TokenParser::tokenized_flag(&tokens, "reverse"));
+        assert!(!TokenParser::tokenized_flag(&tokens, "unique"));
+        assert!(!TokenParser::tokenized_flag(&tokens, "selector"));
+        assert!(!TokenParser::tokenized_flag(&tokens, "sort"));
+        let tokens = TokenParser::tokenize("loop selector='li' separator='reverse' where='unique' var='required'");
+        assert!(!TokenParser::tokenized_flag(&tokens, "reverse"));
+        assert!(!TokenParser::tokenized_flag(&tokens, "unique"));
+        assert!(!TokenParser::tokenized_flag(&tokens, "required"));
+        let tokens = TokenParser::tokenize("loop var='reverse' reverse");
+        assert!(TokenParser::tokenized_flag(&tokens, "reverse"));
+    }
+
 }